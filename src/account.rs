@@ -0,0 +1,232 @@
+use super::iter::Entry;
+use super::secure_trie::*;
+use super::trie::Trie;
+use super::*;
+
+// The hash of empty contract code, i.e. `keccak256("")`.
+pub const EMPTY_CODE_HASH: [u8; 32] = [
+    0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+    0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
+];
+
+// Compute the hash of contract code, i.e. `keccak256(code)`.
+pub fn code_hash(code: &[u8]) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(code);
+    hasher.result().to_vec()
+}
+
+// An Ethereum account, as stored in the leaves of the state tree. The
+// balance is limited to 128 bits, which is more than the total supply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub nonce: u64,
+    pub balance: u128,
+    pub storage_root: Vec<u8>, // Root hash of the account's storage tree
+    pub code_hash: Vec<u8>,    // Hash of the account's contract code
+}
+
+impl Default for Account {
+    // An account with no code and an empty storage tree. Its storage root
+    // is `Trie::new().root_hash()`, like the root of any empty storage
+    // tree. Since nodes are only hashed when their encoding is longer
+    // than 32 bytes, it is the 17-byte encoding of an empty full node,
+    // and not the 32-byte `keccak256(rlp(""))` of Ethereum's empty tree.
+    fn default() -> Self {
+        Account {
+            nonce: 0,
+            balance: 0,
+            storage_root: Trie::new().root_hash(),
+            code_hash: EMPTY_CODE_HASH.to_vec(),
+        }
+    }
+}
+
+impl rlp::Encodable for Account {
+    fn rlp_append(&self, s: &mut rlp::RlpStream) {
+        // Integers are encoded big-endian, without leading zeroes.
+        let balance = self.balance.to_be_bytes();
+        let start = balance
+            .iter()
+            .position(|b| *b != 0)
+            .unwrap_or(balance.len());
+
+        s.begin_list(4);
+        s.append(&self.nonce);
+        s.append(&&balance[start..]);
+        s.append(&self.storage_root);
+        s.append(&self.code_hash);
+    }
+}
+
+impl rlp::Decodable for Account {
+    fn decode(rlp: &rlp::Rlp) -> Result<Self, rlp::DecoderError> {
+        if rlp.item_count()? != 4 {
+            return Err(rlp::DecoderError::RlpIncorrectListLen);
+        }
+
+        let balance = rlp.at(1)?.data()?;
+        if balance.len() > 16 {
+            return Err(rlp::DecoderError::Custom("Balance doesn't fit in 128 bits"));
+        }
+        if !balance.is_empty() && balance[0] == 0 {
+            return Err(rlp::DecoderError::RlpInvalidIndirection);
+        }
+        let mut bytes = [0u8; 16];
+        bytes[16 - balance.len()..].copy_from_slice(balance);
+
+        Ok(Account {
+            nonce: rlp.val_at(0)?,
+            balance: u128::from_be_bytes(bytes),
+            storage_root: rlp.val_at(2)?,
+            code_hash: rlp.val_at(3)?,
+        })
+    }
+}
+
+// Helpers to use a `SecureTrie` as a state tree, i.e. a tree of accounts
+// indexed by the hash of their address.
+impl SecureTrie {
+    pub fn get_account(&self, address: &ByteKey) -> Result<Option<Account>, String> {
+        match self.get(address)? {
+            Some(encoded) => rlp::decode(&encoded)
+                .map(Some)
+                .map_err(|e| format!("Invalid account at {:?}: {:?}", address, e)),
+            None => Ok(None),
+        }
+    }
+
+    // Store `account` at `address`, and return the previous account if
+    // there was one.
+    pub fn insert_account(
+        &mut self,
+        address: &ByteKey,
+        account: &Account,
+    ) -> Result<Option<Account>, String> {
+        let previous = self.get_account(address)?;
+        self.insert(address, rlp::encode(account))?;
+        Ok(previous)
+    }
+
+    // Build a multiproof of the accounts at `addresses`.
+    pub fn prove_accounts<'a, I>(&self, addresses: I) -> Result<Multiproof, String>
+    where
+        I: IntoIterator<Item = &'a ByteKey>,
+    {
+        self.prove(addresses)
+    }
+}
+
+// Check a proof built by `prove_accounts` against the state root, and
+// return the proven accounts along with their hashed address, in hashed
+// address order.
+pub fn verify_accounts(
+    root_hash: &[u8],
+    proof: &Multiproof,
+) -> Result<Vec<(NibbleKey, Account)>, String> {
+    let root = verify_multiproof(root_hash, proof)?;
+    let mut accounts = Vec::new();
+    for entry in root.iter() {
+        if let Entry::Leaf(key, value) = entry {
+            let account =
+                rlp::decode(value).map_err(|e| format!("Invalid account at {:?}: {:?}", key, e))?;
+            accounts.push((key, account));
+        }
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(i: u8) -> ByteKey {
+        ByteKey::from(vec![i; 20])
+    }
+
+    fn account(i: u8) -> Account {
+        Account {
+            nonce: i as u64,
+            balance: 1_000_000_000_000_000_000u128 * i as u128,
+            ..Account::default()
+        }
+    }
+
+    #[test]
+    fn account_rlp_roundtrip() {
+        for balance in [0u128, 1, 255, 256, u128::MAX].iter() {
+            let account = Account {
+                nonce: 7,
+                balance: *balance,
+                storage_root: vec![1u8; 32],
+                code_hash: vec![2u8; 32],
+            };
+            let encoded = rlp::encode(&account);
+            assert_eq!(rlp::decode::<Account>(&encoded).unwrap(), account);
+        }
+    }
+
+    #[test]
+    fn default_storage_root() {
+        let root = Account::default().storage_root;
+        assert_eq!(root, Trie::new().root_hash());
+        assert_eq!(root, SecureTrie::new().root_hash());
+        assert_eq!(root.len(), 17);
+    }
+
+    #[test]
+    fn empty_code_hash() {
+        assert_eq!(code_hash(&[]), EMPTY_CODE_HASH.to_vec());
+    }
+
+    #[test]
+    fn account_rlp_encoding() {
+        let account = Account {
+            nonce: 1,
+            balance: 0x0100,
+            storage_root: vec![],
+            code_hash: vec![],
+        };
+        assert_eq!(
+            rlp::encode(&account),
+            vec![0xc6, 0x01, 0x82, 0x01, 0x00, 0x80, 0x80]
+        );
+        // Balances with leading zeroes are rejected
+        assert!(rlp::decode::<Account>(&[0xc6, 0x01, 0x82, 0x00, 0x01, 0x80, 0x80]).is_err());
+    }
+
+    #[test]
+    fn insert_and_get_accounts() {
+        let mut state = SecureTrie::new();
+        assert_eq!(
+            state.insert_account(&address(1), &account(1)).unwrap(),
+            None
+        );
+        assert_eq!(
+            state.insert_account(&address(1), &account(2)).unwrap(),
+            Some(account(1))
+        );
+        assert_eq!(state.get_account(&address(1)).unwrap(), Some(account(2)));
+        assert_eq!(state.get_account(&address(2)).unwrap(), None);
+
+        state.insert(&address(3), vec![1u8, 2, 3]).unwrap();
+        assert!(state.get_account(&address(3)).is_err());
+    }
+
+    #[test]
+    fn prove_and_verify_accounts() {
+        let mut state = SecureTrie::new();
+        for i in 0..20u8 {
+            state.insert_account(&address(i), &account(i)).unwrap();
+        }
+        let proof = state.prove_accounts(&[address(4), address(11)]).unwrap();
+
+        let accounts = verify_accounts(&state.root_hash(), &proof).unwrap();
+        assert_eq!(accounts.len(), 2);
+        for i in [4u8, 11].iter() {
+            assert!(accounts.contains(&(hash_key(&address(*i)), account(*i))));
+        }
+
+        assert!(verify_accounts(&[0u8; 32], &proof).is_err());
+    }
+}
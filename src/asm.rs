@@ -0,0 +1,177 @@
+use super::*;
+
+// A textual syntax for instruction sequences, with one instruction per
+// line, e.g. `LEAF 31` or `EXTENSION 0a0b`. Everything after a `;` is a
+// comment, and blank lines are ignored. The disassembler annotates each
+// line with the depth of the stack after the instruction is executed:
+//
+//   LEAF 31   ; depth 1
+//   BRANCH 1  ; depth 1
+//   HASHER 0  ; depth 2
+//   ADD 8     ; depth 1
+
+// Number of nodes an instruction pops from the stack, and number of nodes
+// it pushes in their place.
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    use Instruction::*;
+    match instruction {
+        HASHER(_) | LEAF(_) => (0, 1),
+        BRANCH(_) | EXTENSION(_) => (1, 1),
+        ADD(_) => (2, 1),
+    }
+}
+
+// Compute the depth of the stack after each instruction. It fails at the
+// first instruction that doesn't find enough nodes on the stack.
+pub fn stack_depths(instructions: &[Instruction]) -> Result<Vec<usize>, String> {
+    let mut depth = 0usize;
+    let mut depths = Vec::with_capacity(instructions.len());
+    for (i, instruction) in instructions.iter().enumerate() {
+        let (pops, pushes) = stack_effect(instruction);
+        if depth < pops {
+            return Err(format!(
+                "Instruction #{} ({}) needs {} nodes on the stack, found {}",
+                i, instruction, pops, depth
+            ));
+        }
+        depth = depth - pops + pushes;
+        depths.push(depth);
+    }
+    Ok(depths)
+}
+
+// Parse an instruction sequence. The stack depth isn't checked, so that
+// invalid sequences can be written by hand to test the verifier.
+pub fn assemble(text: &str) -> Result<Vec<Instruction>, String> {
+    let mut instructions = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let code = line.split(';').next().unwrap_or("").trim();
+        if code.is_empty() {
+            continue;
+        }
+        let instruction = code
+            .parse::<Instruction>()
+            .map_err(|e| format!("Line {}: {}", i + 1, e))?;
+        instructions.push(instruction);
+    }
+    Ok(instructions)
+}
+
+// Print an instruction sequence, one instruction per line, annotated with
+// the stack depth. Sequences that underflow the stack are printed as well,
+// with the instruction that underflows marked and no depth after it.
+pub fn disassemble(instructions: &[Instruction]) -> String {
+    let lines: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+    let column = lines.iter().map(|l| l.len()).max().unwrap_or(0);
+
+    let mut text = String::new();
+    let mut depth = Some(0usize);
+    for (line, instruction) in lines.iter().zip(instructions.iter()) {
+        let (pops, pushes) = stack_effect(instruction);
+        let annotation = match depth {
+            Some(d) if d >= pops => {
+                depth = Some(d - pops + pushes);
+                format!("depth {}", d - pops + pushes)
+            }
+            Some(_) => {
+                depth = None;
+                "stack underflow".to_string()
+            }
+            None => "depth ?".to_string(),
+        };
+        text.push_str(&format!(
+            "{:<width$} ; {}\n",
+            line,
+            annotation,
+            width = column
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction::*;
+    use super::*;
+
+    #[test]
+    fn disassemble_proof() {
+        let mut root = Node::FullNode(Arc::new(vec![Node::EmptySlot; 16]));
+        root = insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
+        root = insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+        let proof = make_multiproof(&root, vec![(vec![1u8; 32], vec![1u8; 32])]).unwrap();
+
+        let text = disassemble(&proof.instructions);
+        assert_eq!(
+            text,
+            "LEAF 31  ; depth 1\n\
+             BRANCH 1 ; depth 1\n\
+             HASHER 0 ; depth 2\n\
+             ADD 2    ; depth 1\n"
+        );
+        assert_eq!(assemble(&text).unwrap(), proof.instructions);
+        assert_eq!(stack_depths(&proof.instructions).unwrap(), vec![1, 1, 2, 1]);
+    }
+
+    #[test]
+    fn assemble_with_comments() {
+        let text = "
+            ; Two leaves in a full node, under an extension
+            LEAF 1
+            BRANCH 3 ; first leaf
+            LEAF 1
+            ADD 5
+
+            EXTENSION 0a0b
+        ";
+        assert_eq!(
+            assemble(text).unwrap(),
+            vec![
+                LEAF(1),
+                BRANCH(3),
+                LEAF(1),
+                ADD(5),
+                EXTENSION(vec![0xa, 0xb])
+            ]
+        );
+        assert_eq!(assemble("").unwrap(), vec![]);
+
+        let err = assemble("LEAF 1\nBRANCH x\n").unwrap_err();
+        assert!(err.starts_with("Line 2:"), "{}", err);
+    }
+
+    #[test]
+    fn empty_extension_round_trip() {
+        let instructions = vec![LEAF(0), EXTENSION(vec![]), EXTENSION(vec![1, 2])];
+        let text = disassemble(&instructions);
+        assert_eq!(
+            text,
+            "LEAF 0         ; depth 1\n\
+             EXTENSION -    ; depth 1\n\
+             EXTENSION 0102 ; depth 1\n"
+        );
+        assert_eq!(assemble(&text).unwrap(), instructions);
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let instructions = vec![LEAF(0), ADD(1), BRANCH(0), LEAF(0)];
+        assert!(stack_depths(&instructions).is_err());
+        assert_eq!(
+            disassemble(&instructions),
+            "LEAF 0   ; depth 1\n\
+             ADD 1    ; stack underflow\n\
+             BRANCH 0 ; depth ?\n\
+             LEAF 0   ; depth ?\n"
+        );
+
+        // The assembler accepts it, and the verifier rejects it
+        let proof = Multiproof {
+            hashes: vec![],
+            keyvals: vec![rlp::encode(&Node::Leaf(NibbleKey::new(vec![]), vec![1]))],
+            instructions: assemble(&disassemble(&instructions)).unwrap(),
+        };
+        assert_eq!(proof.instructions, instructions);
+        assert!(try_rebuild(&mut vec![], &proof).is_err());
+    }
+}
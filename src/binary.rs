@@ -0,0 +1,504 @@
+use super::*;
+
+// A node of a binary tree. Keys are sequences of bits, each of them
+// stored as a `u8` that is either 0 or 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinaryNode {
+    Hash(Vec<u8>),
+    Leaf(Vec<u8>, Vec<u8>),
+    Extension(Vec<u8>, Arc<BinaryNode>),
+    Branch(Arc<[BinaryNode; 2]>),
+    EmptySlot,
+}
+
+// Node type tags, that come first in the RLP encoding of each node
+// before it is hashed.
+const LEAF_TAG: u8 = 0;
+const BRANCH_TAG: u8 = 2;
+
+// The hash of an empty subtree, which is 32 zero bytes like in SSZ.
+pub const EMPTY_HASH: [u8; 32] = [0u8; 32];
+
+fn keccak(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(data);
+    hasher.result().to_vec()
+}
+
+// Convert a byte key into a bit key, most significant bit first.
+pub fn bytes_to_bits(bytes: &[u8]) -> Vec<u8> {
+    let mut bits = Vec::with_capacity(bytes.len() * 8);
+    for byte in bytes.iter() {
+        for i in (0..8).rev() {
+            bits.push((byte >> i) & 1);
+        }
+    }
+    bits
+}
+
+// Pack a bit key into bytes. The first byte is the number of padding
+// bits at the end of the last byte, so that the key length is preserved.
+fn pack_bits(bits: &[u8]) -> Vec<u8> {
+    let mut packed = vec![((8 - bits.len() % 8) % 8) as u8];
+    for chunk in bits.chunks(8) {
+        let mut byte = 0u8;
+        for (i, bit) in chunk.iter().enumerate() {
+            byte |= bit << (7 - i);
+        }
+        packed.push(byte);
+    }
+    packed
+}
+
+fn check_bits(key: &[u8]) -> Result<(), String> {
+    if key.iter().any(|b| *b > 1) {
+        return Err(format!("Invalid bit in key {:?}", key));
+    }
+    Ok(())
+}
+
+fn common_length(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+impl BinaryNode {
+    // Create a branch whose only non-empty child is `child`, at `bit`.
+    fn branch_with(bit: u8, child: BinaryNode) -> BinaryNode {
+        let mut children = [BinaryNode::EmptySlot, BinaryNode::EmptySlot];
+        children[bit as usize] = child;
+        BinaryNode::Branch(Arc::new(children))
+    }
+
+    // Prefix `node` with `ext`, unless `ext` is empty.
+    fn extend(ext: &[u8], node: BinaryNode) -> BinaryNode {
+        if ext.is_empty() {
+            node
+        } else {
+            BinaryNode::Extension(ext.to_vec(), Arc::new(node))
+        }
+    }
+
+    // The hash of a branch, from the hashes of its children.
+    pub fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+        let mut stream = rlp::RlpStream::new_list(3);
+        stream.append(&BRANCH_TAG).append(&left).append(&right);
+        keccak(&stream.out())
+    }
+
+    // Calculate the hash of the node. Unlike `Node`, the encoding of a
+    // child is always hashed, and empty slots hash to `EMPTY_HASH`.
+    // An extension hashes like the chain of branches it replaces, whose
+    // other children are empty, so that every internal node hashes its
+    // two children like in the SSZ format.
+    pub fn hash(&self) -> Vec<u8> {
+        use BinaryNode::*;
+
+        match self {
+            EmptySlot => EMPTY_HASH.to_vec(),
+            Hash(h) => h.to_vec(),
+            Leaf(key, value) => {
+                let mut stream = rlp::RlpStream::new_list(3);
+                stream
+                    .append(&LEAF_TAG)
+                    .append(&pack_bits(key))
+                    .append(value);
+                keccak(&stream.out())
+            }
+            Extension(ext, child) => ext.iter().rev().fold(child.hash(), |hash, bit| {
+                if *bit == 0 {
+                    Self::hash_pair(&hash, &EMPTY_HASH)
+                } else {
+                    Self::hash_pair(&EMPTY_HASH, &hash)
+                }
+            }),
+            Branch(children) => Self::hash_pair(&children[0].hash(), &children[1].hash()),
+        }
+    }
+
+    // Get the value associated to `key`, if any.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        use BinaryNode::*;
+
+        match self {
+            EmptySlot => Ok(None),
+            Hash(h) => Err(format!("Can not look into hashed node {:?}", h)),
+            Leaf(leafkey, value) => Ok(if leafkey[..] == key[..] {
+                Some(value.to_vec())
+            } else {
+                None
+            }),
+            Extension(ext, child) => {
+                if key.starts_with(ext) {
+                    child.get(&key[ext.len()..])
+                } else {
+                    Ok(None)
+                }
+            }
+            Branch(children) => {
+                if key.is_empty() {
+                    return Err("Key is too short".to_string());
+                }
+                check_bits(&key[..1])?;
+                children[key[0] as usize].get(&key[1..])
+            }
+        }
+    }
+
+    // Return a tree in which `key` is associated to `value`. Only the
+    // nodes along the path of `key` are copied, the other subtrees are
+    // shared with the current tree.
+    pub fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<BinaryNode, String> {
+        use BinaryNode::*;
+
+        check_bits(key)?;
+        match self {
+            EmptySlot => Ok(Leaf(key.to_vec(), value)),
+            Hash(h) => Err(format!("Can not insert into hashed node {:?}", h)),
+            Leaf(leafkey, leafvalue) => {
+                if leafkey[..] == key[..] {
+                    return Ok(Leaf(key.to_vec(), value));
+                }
+                let common = common_length(leafkey, key);
+                if common == leafkey.len() || common == key.len() {
+                    return Err(format!("{:?} and {:?} are prefixes", leafkey, key));
+                }
+                let mut children = [EmptySlot, EmptySlot];
+                children[leafkey[common] as usize] =
+                    Leaf(leafkey[common + 1..].to_vec(), leafvalue.to_vec());
+                children[key[common] as usize] = Leaf(key[common + 1..].to_vec(), value);
+                Ok(Self::extend(&key[..common], Branch(Arc::new(children))))
+            }
+            Extension(ext, child) => {
+                let common = common_length(ext, key);
+                if common == ext.len() {
+                    let child = child.insert(&key[common..], value)?;
+                    return Ok(Extension(ext.to_vec(), Arc::new(child)));
+                }
+                if common == key.len() {
+                    return Err(format!("{:?} is a prefix of other keys", key));
+                }
+                // Split the extension at the first differing bit
+                let mut children = [EmptySlot, EmptySlot];
+                children[ext[common] as usize] =
+                    Self::extend(&ext[common + 1..], BinaryNode::clone(child));
+                children[key[common] as usize] = Leaf(key[common + 1..].to_vec(), value);
+                Ok(Self::extend(&key[..common], Branch(Arc::new(children))))
+            }
+            Branch(children) => {
+                if key.is_empty() {
+                    return Err("Key is too short".to_string());
+                }
+                let mut children = children.clone();
+                let bit = key[0] as usize;
+                Arc::make_mut(&mut children)[bit] = children[bit].insert(&key[1..], value)?;
+                Ok(Branch(children))
+            }
+        }
+    }
+
+    // Build a multiproof of `keyvals`, using the same instructions as
+    // `make_multiproof`. Keys are bit keys, and the selectors of `BRANCH`
+    // and `ADD` are either 0 or 1.
+    pub fn make_multiproof(&self, keyvals: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Multiproof, String> {
+        use BinaryNode::*;
+
+        let mut instructions = Vec::new();
+        let mut values = Vec::new();
+        let mut hashes = Vec::new();
+
+        // If there are no keys specified at this node, then just hash
+        // that node.
+        if keyvals.is_empty() {
+            return Ok(Multiproof {
+                instructions: vec![Instruction::HASHER(0)],
+                hashes: vec![self.hash()],
+                keyvals: vec![],
+            });
+        }
+
+        match self {
+            EmptySlot => return Err("Cannot build a multiproof on an empty slot".to_string()),
+            Hash(h) => return Err(format!("Can not look into hashed node {:?}", h)),
+            Branch(children) => {
+                let mut split = vec![Vec::new(), Vec::new()];
+                for (k, v) in keyvals.iter() {
+                    if k.is_empty() || k[0] > 1 {
+                        return Err(format!("Invalid key in branch: {:?}", k));
+                    }
+                    split[k[0] as usize].push((k[1..].to_vec(), v.to_vec()));
+                }
+
+                // The first child, be it hashed or not, creates the
+                // branch, the second one is added to it.
+                let mut branch = true;
+                for (selector, subkeys) in split.into_iter().enumerate() {
+                    if subkeys.is_empty() {
+                        // Empty slots are not to be hashed
+                        if children[selector] == EmptySlot {
+                            continue;
+                        }
+                        instructions.push(Instruction::HASHER(0));
+                        hashes.push(children[selector].hash());
+                    } else {
+                        let mut proof = children[selector].make_multiproof(subkeys)?;
+                        instructions.append(&mut proof.instructions);
+                        hashes.append(&mut proof.hashes);
+                        values.append(&mut proof.keyvals);
+                    }
+                    if branch {
+                        instructions.push(Instruction::BRANCH(selector));
+                        branch = false;
+                    } else {
+                        instructions.push(Instruction::ADD(selector));
+                    }
+                }
+            }
+            Leaf(leafkey, _) => {
+                if keyvals.len() != 1 {
+                    return Err(format!(
+                        "Expecting exactly 1 key in leaf, got {}: {:?}",
+                        keyvals.len(),
+                        keyvals
+                    ));
+                }
+                let (key, value) = &keyvals[0];
+                if leafkey != key {
+                    return Err(format!(
+                        "Trying to apply the wrong key {:?} != {:?}",
+                        key, leafkey
+                    ));
+                }
+                instructions.push(Instruction::LEAF(key.len()));
+                let mut stream = rlp::RlpStream::new_list(2);
+                stream.append(key).append(value);
+                values.push(stream.out());
+            }
+            Extension(ext, child) => {
+                let mut truncated = vec![];
+                for (k, v) in keyvals.iter() {
+                    if k.len() <= ext.len() || k[..ext.len()] != ext[..] {
+                        return Err(format!(
+                            "One of the keys isn't present in the tree: {:?}",
+                            k
+                        ));
+                    }
+                    truncated.push((k[ext.len()..].to_vec(), v.to_vec()));
+                }
+                let mut proof = child.make_multiproof(truncated)?;
+                hashes.append(&mut proof.hashes);
+                instructions.append(&mut proof.instructions);
+                instructions.push(Instruction::EXTENSION(ext.to_vec()));
+                values.append(&mut proof.keyvals);
+            }
+        }
+
+        Ok(Multiproof {
+            instructions,
+            hashes,
+            keyvals: values,
+        })
+    }
+
+    // Rebuild a binary tree from a multiproof built by `make_multiproof`.
+    pub fn rebuild(proof: &Multiproof) -> Result<BinaryNode, String> {
+        use BinaryNode::*;
+        use Instruction::*;
+
+        let mut stack = Vec::new();
+        let mut hiter = proof.hashes.iter();
+        let mut kviter = proof.keyvals.iter();
+
+        for instr in proof.instructions.iter() {
+            match instr {
+                HASHER(_) => {
+                    let h = hiter
+                        .next()
+                        .ok_or_else(|| "Proof requires one more hash in HASHER".to_string())?;
+                    stack.push(Hash(h.to_vec()));
+                }
+                LEAF(keylength) => {
+                    let encoded = kviter.next().ok_or_else(|| {
+                        "Proof requires one more (key,value) pair in LEAF".to_string()
+                    })?;
+                    let keyval = rlp::Rlp::new(encoded)
+                        .as_list::<Vec<u8>>()
+                        .map_err(|e| format!("Invalid (key,value) pair in LEAF: {:?}", e))?;
+                    if keyval.len() != 2 || *keylength > keyval[0].len() {
+                        return Err("Invalid (key,value) pair in LEAF".to_string());
+                    }
+                    let key = &keyval[0][keyval[0].len() - keylength..];
+                    check_bits(key)?;
+                    stack.push(Leaf(key.to_vec(), keyval[1].to_vec()));
+                }
+                BRANCH(digit) => {
+                    if *digit > 1 {
+                        return Err(format!("Incorrect branch index: {} > 1", digit));
+                    }
+                    let node = stack.pop().ok_or_else(|| {
+                        "Could not pop a value from the stack, that is required for a BRANCH"
+                            .to_string()
+                    })?;
+                    stack.push(Self::branch_with(*digit as u8, node));
+                }
+                EXTENSION(ext) => {
+                    check_bits(ext)?;
+                    let node = stack.pop().ok_or_else(|| {
+                        "Could not find a node on the stack, that is required for an EXTENSION"
+                            .to_string()
+                    })?;
+                    stack.push(Extension(ext.to_vec(), Arc::new(node)));
+                }
+                ADD(digit) => {
+                    if *digit > 1 {
+                        return Err(format!("Incorrect branch index: {} > 1", digit));
+                    }
+                    match (stack.pop(), stack.last_mut()) {
+                        (Some(node), Some(Branch(children))) => {
+                            Arc::make_mut(children)[*digit] = node;
+                        }
+                        (Some(_), Some(_)) => return Err("Unexpected node type".to_string()),
+                        _ => return Err("Could not find enough parameters to ADD".to_string()),
+                    }
+                }
+            }
+        }
+
+        stack
+            .pop()
+            .ok_or_else(|| "Proof did not produce any node".to_string())
+    }
+}
+
+impl VerifyMultiproof for BinaryNode {
+    type Proof = Multiproof;
+    type Proven = BinaryNode;
+
+    // Rebuild a binary tree from a multiproof, and check that its hash
+    // is `root_hash`.
+    fn verify_multiproof(root_hash: &[u8], proof: &Multiproof) -> Result<BinaryNode, String> {
+        let root = Self::rebuild(proof)?;
+        let hash = root.hash();
+        if hash != root_hash {
+            return Err(format!(
+                "Invalid proof: expected root hash {:?}, got {:?}",
+                root_hash, hash
+            ));
+        }
+        Ok(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BinaryNode::*;
+    use super::*;
+
+    fn key(i: u8) -> Vec<u8> {
+        let mut hasher = Keccak256::new();
+        hasher.input([i]);
+        bytes_to_bits(&hasher.result()[..])
+    }
+
+    fn sample_tree(count: u8) -> BinaryNode {
+        let mut root = EmptySlot;
+        for i in 0..count {
+            root = root.insert(&key(i), vec![i; 32]).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn bits_conversion() {
+        assert_eq!(bytes_to_bits(&[0xa5]), vec![1, 0, 1, 0, 0, 1, 0, 1]);
+        assert_eq!(pack_bits(&[1, 0, 1]), vec![5, 0xa0]);
+        assert_eq!(pack_bits(&bytes_to_bits(&[0xa5])), vec![0, 0xa5]);
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let root = sample_tree(50);
+        for i in 0..50u8 {
+            assert_eq!(root.get(&key(i)).unwrap(), Some(vec![i; 32]));
+        }
+        assert_eq!(root.get(&key(50)).unwrap(), None);
+
+        let updated = root.insert(&key(3), vec![0u8; 32]).unwrap();
+        assert_eq!(updated.get(&key(3)).unwrap(), Some(vec![0u8; 32]));
+        assert_eq!(root.get(&key(3)).unwrap(), Some(vec![3u8; 32]));
+
+        assert!(root.insert(&key(3)[..10], vec![1u8]).is_err());
+        assert!(root.insert(&[2u8], vec![1u8]).is_err());
+    }
+
+    #[test]
+    fn hash_is_independent_of_insertion_order() {
+        let mut root = EmptySlot;
+        for i in (0..50u8).rev() {
+            root = root.insert(&key(i), vec![i; 32]).unwrap();
+        }
+        assert_eq!(root.hash(), sample_tree(50).hash());
+        assert_ne!(root.hash(), sample_tree(49).hash());
+    }
+
+    #[test]
+    fn extension_hashes_like_branches() {
+        let leaf = Leaf(vec![1, 0], vec![1u8]);
+        let ext = Extension(vec![0, 1], Arc::new(leaf.clone()));
+        let branches = Branch(Arc::new([Branch(Arc::new([EmptySlot, leaf])), EmptySlot]));
+        assert_eq!(ext.hash(), branches.hash());
+    }
+
+    #[test]
+    fn extension_split() {
+        let root = EmptySlot
+            .insert(&[0, 0, 0, 0], vec![1u8])
+            .unwrap()
+            .insert(&[0, 0, 0, 1], vec![2u8])
+            .unwrap()
+            .insert(&[0, 1, 1, 1], vec![3u8])
+            .unwrap();
+        match &root {
+            Extension(ext, child) => {
+                assert_eq!(*ext, vec![0]);
+                match &**child {
+                    Branch(children) => match &children[0] {
+                        Extension(ext, _) => assert_eq!(*ext, vec![0]),
+                        _ => panic!("Expected an extension, got {:?}", children[0]),
+                    },
+                    _ => panic!("Expected a branch, got {:?}", child),
+                }
+            }
+            _ => panic!("Expected an extension, got {:?}", root),
+        }
+        assert_eq!(root.get(&[0, 0, 0, 1]).unwrap(), Some(vec![2u8]));
+        assert_eq!(root.get(&[0, 1, 1, 1]).unwrap(), Some(vec![3u8]));
+    }
+
+    #[test]
+    fn multiproof_roundtrip() {
+        let root = sample_tree(50);
+        let mut keyvals: Vec<(Vec<u8>, Vec<u8>)> = [4u8, 17, 33]
+            .iter()
+            .map(|i| (key(*i), vec![*i; 32]))
+            .collect();
+        keyvals.sort();
+
+        let proof = root.make_multiproof(keyvals.clone()).unwrap();
+        let rebuilt = BinaryNode::verify_multiproof(&root.hash(), &proof).unwrap();
+        for (k, v) in keyvals.iter() {
+            assert_eq!(rebuilt.get(k).unwrap(), Some(v.clone()));
+        }
+        assert!(rebuilt.get(&key(5)).is_err());
+
+        assert!(BinaryNode::verify_multiproof(&[0u8; 32], &proof).is_err());
+        let mut bad = proof.clone();
+        bad.instructions.push(Instruction::ADD(2));
+        assert!(BinaryNode::rebuild(&bad).is_err());
+    }
+
+    #[test]
+    fn multiproof_missing_key() {
+        let root = sample_tree(10);
+        assert!(root.make_multiproof(vec![(key(20), vec![1u8])]).is_err());
+    }
+}
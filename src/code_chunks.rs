@@ -0,0 +1,191 @@
+use super::iter::Entry;
+use super::trie::Trie;
+use super::*;
+
+// The size of a code chunk, in bytes.
+pub const CHUNK_SIZE: usize = 32;
+
+const PUSH1: u8 = 0x60;
+const PUSH32: u8 = 0x7f;
+
+// A fixed-size piece of contract code. Since a chunk can start in the
+// middle of the data of a PUSH instruction, it records the offset of its
+// first instruction, so that the code can be executed from a chunk
+// without knowing the previous ones. If the whole chunk is PUSH data,
+// the offset is the length of the chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub first_instruction_offset: u8,
+    pub code: Vec<u8>,
+}
+
+impl Chunk {
+    // The value stored in the code tree: the offset followed by the code.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(self.code.len() + 1);
+        encoded.push(self.first_instruction_offset);
+        encoded.extend_from_slice(&self.code);
+        encoded
+    }
+
+    pub fn decode(encoded: &[u8]) -> Result<Self, String> {
+        if encoded.is_empty() || encoded.len() > CHUNK_SIZE + 1 {
+            return Err(format!("Invalid chunk length {}", encoded.len()));
+        }
+        if encoded[0] as usize > encoded.len() - 1 {
+            return Err(format!("Invalid first instruction offset {}", encoded[0]));
+        }
+        Ok(Chunk {
+            first_instruction_offset: encoded[0],
+            code: encoded[1..].to_vec(),
+        })
+    }
+}
+
+// Split contract code into chunks of `CHUNK_SIZE` bytes. The last chunk
+// can be shorter.
+pub fn chunkify(code: &[u8]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    // Position of the next instruction in the code
+    let mut pc = 0;
+    for (i, piece) in code.chunks(CHUNK_SIZE).enumerate() {
+        let start = i * CHUNK_SIZE;
+        let end = start + piece.len();
+        let offset = std::cmp::min(pc, end) - start;
+        while pc < end {
+            let opcode = code[pc];
+            pc += 1;
+            if (PUSH1..=PUSH32).contains(&opcode) {
+                pc += (opcode - PUSH1 + 1) as usize;
+            }
+        }
+        chunks.push(Chunk {
+            first_instruction_offset: offset as u8,
+            code: piece.to_vec(),
+        });
+    }
+    chunks
+}
+
+// The key of a chunk in the code tree: its index, big-endian.
+fn chunk_key(index: usize) -> NibbleKey {
+    NibbleKey::from(ByteKey::from((index as u32).to_be_bytes().to_vec()))
+}
+
+// Build the tree of the chunks of `code`, indexed by chunk number.
+pub fn code_trie(code: &[u8]) -> Trie {
+    chunkify(code)
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| (chunk_key(i), chunk.encode()))
+        .collect()
+}
+
+// The root hash of the code tree of `code`.
+pub fn code_root(code: &[u8]) -> Vec<u8> {
+    code_trie(code).root_hash()
+}
+
+// The index of the chunk holding the byte at `pc`.
+pub fn chunk_index(pc: usize) -> usize {
+    pc / CHUNK_SIZE
+}
+
+// Build a multiproof of the chunks at indices `chunks`, e.g. the chunks
+// that were executed.
+pub fn prove_chunks(code: &[u8], chunks: &[usize]) -> Result<Multiproof, String> {
+    code_trie(code).prove(chunks.iter().map(|i| chunk_key(*i)))
+}
+
+// Check a proof built by `prove_chunks` against the code root, and return
+// the proven chunks along with their index, in index order.
+pub fn verify_chunks(root_hash: &[u8], proof: &Multiproof) -> Result<Vec<(usize, Chunk)>, String> {
+    let root = verify_multiproof(root_hash, proof)?;
+    let mut chunks = Vec::new();
+    for entry in root.iter() {
+        if let Entry::Leaf(key, value) = entry {
+            let key = ByteKey::from(key);
+            if key.as_ref().len() != 4 {
+                return Err(format!("Invalid chunk key {:?}", key));
+            }
+            let mut index = [0u8; 4];
+            index.copy_from_slice(key.as_ref());
+            chunks.push((u32::from_be_bytes(index) as usize, Chunk::decode(value)?));
+        }
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 50 bytes of code whose PUSH32 data straddles the first two chunks
+    fn sample_code() -> Vec<u8> {
+        let mut code = vec![0x5b; 10]; // JUMPDEST
+        code.push(PUSH32);
+        code.extend_from_slice(&[0xff; 32]);
+        code.extend_from_slice(&[0x60, 0x01, 0x01, 0x00]); // PUSH1 1, ADD, STOP
+        code.extend_from_slice(&[0x7f, 0x00, 0x00]); // Truncated PUSH32
+        code
+    }
+
+    #[test]
+    fn chunkify_offsets() {
+        let chunks = chunkify(&sample_code());
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].first_instruction_offset, 0);
+        assert_eq!(chunks[0].code.len(), CHUNK_SIZE);
+        // PUSH32 is at 10, so its data runs through 42, and the next
+        // instruction starts at 43 = 32 + 11
+        assert_eq!(chunks[1].first_instruction_offset, 11);
+        assert_eq!(chunks[1].code.len(), 18);
+
+        // The data of the first PUSH32 ends one byte into the second chunk
+        let mut code = vec![PUSH32; 1];
+        code.extend_from_slice(&[PUSH32; 32]);
+        code.extend_from_slice(&[PUSH32; 32]);
+        let chunks = chunkify(&code);
+        assert_eq!(chunks[1].first_instruction_offset, 1);
+
+        // A chunk made entirely of PUSH data
+        let mut code = vec![0x00; 31];
+        code.push(PUSH32);
+        code.extend_from_slice(&[0x01; 40]);
+        let chunks = chunkify(&code);
+        assert_eq!(chunks[1].first_instruction_offset, 32);
+        assert_eq!(chunks[2].first_instruction_offset, 0);
+    }
+
+    #[test]
+    fn chunk_encoding_roundtrip() {
+        for chunk in chunkify(&sample_code()).iter() {
+            assert_eq!(Chunk::decode(&chunk.encode()).unwrap(), *chunk);
+        }
+        assert!(Chunk::decode(&[]).is_err());
+        assert!(Chunk::decode(&[3, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn prove_executed_chunks() {
+        let mut code = sample_code();
+        code.extend_from_slice(&[0x5b; 200]);
+        let root = code_root(&code);
+        let chunks = chunkify(&code);
+
+        let executed = vec![chunk_index(0), chunk_index(150), chunk_index(45)];
+        let proof = prove_chunks(&code, &executed).unwrap();
+        let proven = verify_chunks(&root, &proof).unwrap();
+        assert_eq!(
+            proven,
+            vec![
+                (0, chunks[0].clone()),
+                (1, chunks[1].clone()),
+                (4, chunks[4].clone())
+            ]
+        );
+
+        assert!(verify_chunks(&code_root(&sample_code()), &proof).is_err());
+        assert!(prove_chunks(&code, &[100]).is_err());
+    }
+}
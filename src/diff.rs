@@ -0,0 +1,314 @@
+use super::*;
+use std::cmp::Ordering;
+
+// The differences between two trees.
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub added: Vec<(NibbleKey, Vec<u8>)>, // Keys that are only present in the new tree
+    pub removed: Vec<(NibbleKey, Vec<u8>)>, // Keys that are only present in the old tree
+    pub modified: Vec<(NibbleKey, Vec<u8>, Vec<u8>)>, // Keys present in both, with (old, new) values
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+// Collect all the `(key, value)` pairs of a subtree, in key order.
+fn collect_leaves<R: Resolver>(
+    node: &Node,
+    prefix: Vec<u8>,
+    resolver: &R,
+    out: &mut Vec<(Vec<u8>, Vec<u8>)>,
+) -> Result<(), String> {
+    use Node::*;
+
+    match node {
+        EmptySlot => {}
+        Leaf(leafkey, value) => {
+            let mut key = prefix;
+            key.extend_from_slice(&Into::<Vec<u8>>::into(leafkey.clone()));
+            out.push((key, value.to_vec()));
+        }
+        Extension(ext, child) => {
+            let mut key = prefix;
+            key.extend_from_slice(ext);
+            collect_leaves(child, key, resolver, out)?;
+        }
+        FullNode(children) => {
+            for (i, child) in children.iter().enumerate() {
+                let mut key = prefix.clone();
+                key.push(i as u8);
+                collect_leaves(child, key, resolver, out)?;
+            }
+        }
+        Hash(h, _) => collect_leaves(&resolve_hash(resolver, h)?, prefix, resolver, out)?,
+    }
+    Ok(())
+}
+
+// Whether two subtrees are known to be identical without looking into
+// them. Snapshots share the subtrees that weren't modified, so those are
+// recognized by pointer; other subtrees are compared one level at a time
+// by `diff_nodes`, which only recurses into the children that differ.
+fn same_subtree(old: &Node, new: &Node) -> bool {
+    use Node::*;
+
+    match (old, new) {
+        (FullNode(o), FullNode(n)) => Arc::ptr_eq(o, n),
+        (Extension(oext, o), Extension(next, n)) => oext == next && Arc::ptr_eq(o, n),
+        (Hash(o, _), Hash(n, _)) => o == n,
+        // Leaves and empty slots, or nodes of different kinds
+        _ => old == new,
+    }
+}
+
+struct Differ<'a, R: Resolver> {
+    resolver: &'a R,
+    diff: Diff,
+    // Number of pairs of nodes that had to be looked into
+    visited: usize,
+}
+
+impl<R: Resolver> Differ<'_, R> {
+    fn diff_nodes(&mut self, old: &Node, new: &Node, prefix: Vec<u8>) -> Result<(), String> {
+        use Node::*;
+
+        if same_subtree(old, new) {
+            return Ok(());
+        }
+        self.visited += 1;
+
+        match (old, new) {
+            // A hashed subtree is only resolved if it differs from the
+            // other side, which then has to be hashed.
+            (Hash(h, _), _) => {
+                if *h == new.hash(&mut vec![]) {
+                    return Ok(());
+                }
+                self.diff_nodes(&resolve_hash(self.resolver, h)?, new, prefix)
+            }
+            (_, Hash(h, _)) => {
+                if old.hash(&mut vec![]) == *h {
+                    return Ok(());
+                }
+                self.diff_nodes(old, &resolve_hash(self.resolver, h)?, prefix)
+            }
+            (FullNode(oldchildren), FullNode(newchildren))
+                if oldchildren.len() == newchildren.len() =>
+            {
+                for (i, (o, n)) in oldchildren.iter().zip(newchildren.iter()).enumerate() {
+                    let mut key = prefix.clone();
+                    key.push(i as u8);
+                    self.diff_nodes(o, n, key)?;
+                }
+                Ok(())
+            }
+            (Extension(oldext, oldchild), Extension(newext, newchild)) if oldext == newext => {
+                let mut key = prefix;
+                key.extend_from_slice(oldext);
+                self.diff_nodes(oldchild, newchild, key)
+            }
+            _ => {
+                // The structure of both subtrees is different: compare
+                // their sorted lists of leaves.
+                let mut oldleaves = Vec::new();
+                let mut newleaves = Vec::new();
+                collect_leaves(old, prefix.clone(), self.resolver, &mut oldleaves)?;
+                collect_leaves(new, prefix, self.resolver, &mut newleaves)?;
+
+                let diff = &mut self.diff;
+                let mut olditer = oldleaves.into_iter().peekable();
+                let mut newiter = newleaves.into_iter().peekable();
+                loop {
+                    let order = match (olditer.peek(), newiter.peek()) {
+                        (None, None) => break,
+                        (Some(_), None) => Ordering::Less,
+                        (None, Some(_)) => Ordering::Greater,
+                        (Some((o, _)), Some((n, _))) => o.cmp(n),
+                    };
+                    match order {
+                        Ordering::Less => {
+                            let (k, v) = olditer.next().unwrap();
+                            diff.removed.push((NibbleKey::from_digits_unchecked(k), v));
+                        }
+                        Ordering::Greater => {
+                            let (k, v) = newiter.next().unwrap();
+                            diff.added.push((NibbleKey::from_digits_unchecked(k), v));
+                        }
+                        Ordering::Equal => {
+                            let (k, o) = olditer.next().unwrap();
+                            let (_, n) = newiter.next().unwrap();
+                            if o != n {
+                                diff.modified
+                                    .push((NibbleKey::from_digits_unchecked(k), o, n));
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Compute the keys that have been added, removed or modified between
+// the `old` and `new` trees, resolving the `Hash` nodes of subtrees
+// that differ with `resolver`. Identical subtrees are skipped, so that
+// the cost of comparing two snapshots of a tree is proportional to the
+// number of nodes that differ between them.
+pub fn diff_with_resolver<R: Resolver>(
+    old: &Node,
+    new: &Node,
+    resolver: &R,
+) -> Result<Diff, String> {
+    let mut differ = Differ {
+        resolver,
+        diff: Diff::default(),
+        visited: 0,
+    };
+    differ.diff_nodes(old, new, vec![])?;
+    Ok(differ.diff)
+}
+
+// Compute the differences between two trees. An error is returned if
+// a `Hash` node has to be looked into.
+pub fn diff(old: &Node, new: &Node) -> Result<Diff, String> {
+    diff_with_resolver(old, new, &NoResolver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store::*;
+    use super::Node::*;
+    use super::*;
+
+    fn key(first: u8, rest: u8) -> Vec<u8> {
+        let mut key = vec![rest; 32];
+        key[0] = first;
+        key
+    }
+
+    fn tree(keyvals: &[(Vec<u8>, Vec<u8>)]) -> Node {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        for (k, v) in keyvals.iter() {
+            insert_leaf(&mut root, k.clone(), v.clone()).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn diff_identical_trees() {
+        let old = tree(&[(key(1, 1), vec![1u8; 32]), (key(2, 2), vec![2u8; 32])]);
+        let new = old.clone();
+        assert!(diff(&old, &new).unwrap().is_empty());
+
+        // A hashed version of the same tree is skipped
+        let hashed = Hash(old.hash(&mut vec![]), 0);
+        assert!(diff(&old, &hashed).unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_added_removed_modified() {
+        let old = tree(&[
+            (key(1, 1), vec![1u8; 32]),
+            (key(1, 2), vec![2u8; 32]),
+            (key(5, 5), vec![5u8; 32]),
+            (key(8, 8), vec![8u8; 32]),
+        ]);
+        let new = tree(&[
+            (key(1, 1), vec![1u8; 32]),
+            (key(1, 3), vec![3u8; 32]),
+            (key(5, 5), vec![6u8; 32]),
+            (key(8, 8), vec![8u8; 32]),
+            (key(9, 9), vec![9u8; 32]),
+        ]);
+
+        let d = diff(&old, &new).unwrap();
+        assert_eq!(
+            d.added,
+            vec![
+                (NibbleKey::new(key(1, 3)), vec![3u8; 32]),
+                (NibbleKey::new(key(9, 9)), vec![9u8; 32])
+            ]
+        );
+        assert_eq!(d.removed, vec![(NibbleKey::new(key(1, 2)), vec![2u8; 32])]);
+        assert_eq!(
+            d.modified,
+            vec![(NibbleKey::new(key(5, 5)), vec![5u8; 32], vec![6u8; 32])]
+        );
+    }
+
+    #[test]
+    fn diff_leaf_split_into_subtree() {
+        let mut other = key(1, 1);
+        other[20] = 0;
+        let old = tree(&[(key(1, 1), vec![1u8; 32])]);
+        let new = tree(&[(key(1, 1), vec![1u8; 32]), (other.clone(), vec![0u8; 32])]);
+
+        let d = diff(&old, &new).unwrap();
+        assert_eq!(d.added, vec![(NibbleKey::new(other), vec![0u8; 32])]);
+        assert!(d.removed.is_empty());
+        assert!(d.modified.is_empty());
+    }
+
+    #[test]
+    fn diff_resolves_differing_hashes() {
+        let old = tree(&[(key(1, 1), vec![1u8; 32]), (key(2, 2), vec![2u8; 32])]);
+        let new = tree(&[(key(1, 1), vec![1u8; 32]), (key(2, 2), vec![3u8; 32])]);
+        let mut store = MemoryStore::new();
+        let hash = store_tree(&mut store, &old).unwrap();
+        let hashed = Hash(hash, 0);
+
+        assert!(diff(&hashed, &new).is_err());
+        let d = diff_with_resolver(&hashed, &new, &store).unwrap();
+        assert_eq!(
+            d.modified,
+            vec![(NibbleKey::new(key(2, 2)), vec![2u8; 32], vec![3u8; 32])]
+        );
+    }
+
+    #[test]
+    fn diff_snapshot_only_visits_modified_path() {
+        let mut keyvals = Vec::new();
+        for first in 0..16u8 {
+            for second in 0..16u8 {
+                let mut k = key(first, 3);
+                k[1] = second;
+                keyvals.push((k, vec![first; 32]));
+            }
+        }
+        let old = tree(&keyvals);
+
+        // Snapshot the tree and modify one leaf of the copy
+        let mut changed = key(7, 3);
+        changed[1] = 9;
+        let mut new = remove_leaf(&old, &changed).unwrap();
+        new = insert_leaf(&mut new, changed.clone(), vec![0u8; 32]).unwrap();
+
+        let mut differ = Differ {
+            resolver: &NoResolver,
+            diff: Diff::default(),
+            visited: 0,
+        };
+        differ.diff_nodes(&old, &new, vec![]).unwrap();
+        assert_eq!(
+            differ.diff.modified,
+            vec![(NibbleKey::new(changed), vec![7u8; 32], vec![0u8; 32])]
+        );
+        // The root, the full node holding the leaf, and the leaf itself
+        assert_eq!(differ.visited, 3);
+
+        // Identical trees that share nothing are compared level by level,
+        // visiting each pair of internal nodes once.
+        let mut differ = Differ {
+            resolver: &NoResolver,
+            diff: Diff::default(),
+            visited: 0,
+        };
+        differ.diff_nodes(&old, &tree(&keyvals), vec![]).unwrap();
+        assert!(differ.diff.is_empty());
+        assert_eq!(differ.visited, 17);
+    }
+}
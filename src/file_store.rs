@@ -0,0 +1,383 @@
+use super::store::*;
+use super::{Node, Resolver};
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Record tags in the log file.
+const NODE_RECORD: u8 = 0;
+const ROOT_RECORD: u8 = 1;
+const DELETE_RECORD: u8 = 2;
+
+// Size of the record header: a tag byte and a 4-byte payload length.
+const HEADER_SIZE: usize = 5;
+// Size of the checksum that follows each payload.
+const CHECKSUM_SIZE: usize = 4;
+
+fn checksum(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input([tag]);
+    hasher.input(payload);
+    hasher.result()[..CHECKSUM_SIZE].to_vec()
+}
+
+fn write_record<W: Write>(out: &mut W, tag: u8, payload: &[u8]) -> Result<u64, String> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + payload.len() + CHECKSUM_SIZE);
+    record.push(tag);
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(payload);
+    record.extend_from_slice(&checksum(tag, payload));
+    out.write_all(&record).map_err(|e| e.to_string())?;
+    Ok(record.len() as u64)
+}
+
+fn node_payload(hash: &[u8], encoding: &[u8]) -> Vec<u8> {
+    let mut stream = rlp::RlpStream::new_list(2);
+    stream.append(&hash.to_vec()).append(&encoding.to_vec());
+    stream.out()
+}
+
+// An append-only, file-backed node store.
+//
+// Every `put` appends a record containing the node's hash and its
+// storage encoding to the log file, and `commit` appends a record
+// holding the new root hash before syncing the file to disk. Deleted
+// nodes are marked by a record holding their hash. The index of node
+// positions is rebuilt by scanning the log when the file is opened.
+// A record that was only partially written, e.g. because of a crash,
+// is detected by its checksum and truncated away, along with anything
+// following it. Therefore, the root that is reported after a crash is
+// always the last one whose `commit` returned.
+#[derive(Debug)]
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+    // Offset and length of each node's payload in the log.
+    index: HashMap<Vec<u8>, (u64, usize)>,
+    // Committed roots, from the oldest to the most recent one.
+    roots: Vec<Vec<u8>>,
+    size: u64,
+}
+
+impl FileStore {
+    // Open the log file at `path`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).map_err(|e| e.to_string())?;
+
+        let mut index = HashMap::new();
+        let mut roots = Vec::new();
+        let mut offset = 0usize;
+        while offset + HEADER_SIZE <= data.len() {
+            let tag = data[offset];
+            let mut len = [0u8; 4];
+            len.copy_from_slice(&data[offset + 1..offset + HEADER_SIZE]);
+            let len = u32::from_be_bytes(len) as usize;
+            let start = offset + HEADER_SIZE;
+            let end = start + len;
+            if end + CHECKSUM_SIZE > data.len()
+                || data[end..end + CHECKSUM_SIZE] != checksum(tag, &data[start..end])[..]
+            {
+                break;
+            }
+
+            match tag {
+                NODE_RECORD => {
+                    let hash = rlp::Rlp::new(&data[start..end])
+                        .val_at::<Vec<u8>>(0)
+                        .map_err(|e| format!("{:?}", e))?;
+                    index.insert(hash, (start as u64, len));
+                }
+                ROOT_RECORD => roots.push(data[start..end].to_vec()),
+                DELETE_RECORD => {
+                    let hash = &data[start..end];
+                    index.remove(hash);
+                    roots.retain(|r| &r[..] != hash);
+                }
+                _ => return Err(format!("Invalid record tag {} at offset {}", tag, offset)),
+            }
+            offset = end + CHECKSUM_SIZE;
+        }
+
+        // Drop the incomplete record at the end of the log, if any.
+        if offset < data.len() {
+            file.set_len(offset as u64).map_err(|e| e.to_string())?;
+            file.sync_all().map_err(|e| e.to_string())?;
+        }
+
+        Ok(FileStore {
+            path,
+            file,
+            index,
+            roots,
+            size: offset as u64,
+        })
+    }
+
+    // The last committed root hash, if any.
+    pub fn root(&self) -> Option<&[u8]> {
+        self.roots.last().map(|r| &r[..])
+    }
+
+    // All the committed roots whose node hasn't been deleted, from the
+    // oldest to the most recent one. This can be used to restore the
+    // `RootHistory` of the store after it has been reopened.
+    pub fn roots(&self) -> &[Vec<u8>] {
+        &self.roots
+    }
+
+    // Number of distinct nodes in the store.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // Size of the log file, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Record `root` as the new root of the tree and make sure that it,
+    // and all the nodes that were written before it, are on disk.
+    pub fn commit(&mut self, root: &[u8]) -> Result<(), String> {
+        self.append(ROOT_RECORD, root)?;
+        self.file.sync_all().map_err(|e| e.to_string())?;
+        self.roots.push(root.to_vec());
+        Ok(())
+    }
+
+    // Rewrite the log so that it only contains the latest copy of each
+    // live node and the committed roots. The new log is written to a
+    // temporary file that atomically replaces the current one once it
+    // is synced.
+    pub fn compact(&mut self) -> Result<(), String> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut tmp = File::create(&tmp_path).map_err(|e| e.to_string())?;
+        let mut hashes: Vec<&Vec<u8>> = self.index.keys().collect();
+        hashes.sort();
+        for hash in hashes {
+            let encoding = self.read_encoding(hash)?;
+            write_record(&mut tmp, NODE_RECORD, &node_payload(hash, &encoding))?;
+        }
+        for root in self.roots.iter() {
+            write_record(&mut tmp, ROOT_RECORD, root)?;
+        }
+        tmp.sync_all().map_err(|e| e.to_string())?;
+        drop(tmp);
+
+        fs::rename(&tmp_path, &self.path).map_err(|e| e.to_string())?;
+        // The rename itself is only durable once the directory is synced
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|e| e.to_string())?;
+        *self = FileStore::open(&self.path)?;
+        Ok(())
+    }
+
+    // Append a record to the log, and return the offset of its payload.
+    // If the write fails, the log is truncated back to its previous size
+    // so that the file stays in step with `size` and the index.
+    fn append(&mut self, tag: u8, payload: &[u8]) -> Result<u64, String> {
+        match write_record(&mut self.file, tag, payload) {
+            Ok(len) => {
+                let start = self.size + HEADER_SIZE as u64;
+                self.size += len;
+                Ok(start)
+            }
+            Err(e) => {
+                self.file
+                    .set_len(self.size)
+                    .map_err(|t| format!("{}, then failed to truncate the log: {}", e, t))?;
+                Err(e)
+            }
+        }
+    }
+
+    fn read_encoding(&self, hash: &[u8]) -> Result<Vec<u8>, String> {
+        let (offset, len) = self.index[hash];
+        let mut payload = vec![0u8; len];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| e.to_string())?;
+        file.read_exact(&mut payload).map_err(|e| e.to_string())?;
+        rlp::Rlp::new(&payload)
+            .val_at::<Vec<u8>>(1)
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
+impl NodeStore for FileStore {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if self.index.contains_key(hash) {
+            self.read_encoding(hash).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn put(&mut self, hash: Vec<u8>, encoding: Vec<u8>) -> Result<(), String> {
+        let payload = node_payload(&hash, &encoding);
+        let start = self.append(NODE_RECORD, &payload)?;
+        self.index.insert(hash, (start, payload.len()));
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &[u8]) -> Result<(), String> {
+        if self.index.contains_key(hash) {
+            self.append(DELETE_RECORD, hash)?;
+            self.index.remove(hash);
+            self.roots.retain(|r| &r[..] != hash);
+        }
+        Ok(())
+    }
+
+    fn hashes(&self) -> Vec<Vec<u8>> {
+        self.index.keys().cloned().collect()
+    }
+}
+
+impl Resolver for FileStore {
+    fn resolve(&self, hash: &[u8]) -> Result<Node, String> {
+        load_node(self, hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Node::*;
+    use super::super::*;
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("multiproof-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn tree_with(keys: &[u8]) -> Node {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        for k in keys {
+            insert_leaf(&mut root, vec![*k; 32], vec![*k; 32]).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn reopen_restores_root_and_nodes() {
+        let path = temp_path("reopen");
+        let root = tree_with(&[1, 2, 8]);
+        let hash = {
+            let mut store = FileStore::open(&path).unwrap();
+            let hash = store_tree(&mut store, &root).unwrap();
+            store.commit(&hash).unwrap();
+            hash
+        };
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.root(), Some(&hash[..]));
+        assert_eq!(load_tree(&store, &hash).unwrap(), root);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_record_is_truncated() {
+        let path = temp_path("torn");
+        let first = tree_with(&[1, 2]);
+        let (hash, committed_size) = {
+            let mut store = FileStore::open(&path).unwrap();
+            let hash = store_tree(&mut store, &first).unwrap();
+            store.commit(&hash).unwrap();
+            (hash, store.size())
+        };
+
+        // Simulate a crash in the middle of writing a record
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[NODE_RECORD, 0, 0, 1, 0, 42, 42]).unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.root(), Some(&hash[..]));
+        assert_eq!(store.size(), committed_size);
+        assert_eq!(fs::metadata(&path).unwrap().len(), committed_size);
+        assert_eq!(load_tree(&store, &hash).unwrap(), first);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compact_removes_duplicates() {
+        let path = temp_path("compact");
+        let root = tree_with(&[1, 2, 8]);
+        let mut store = FileStore::open(&path).unwrap();
+        let leaf = Leaf(NibbleKey::new(vec![3u8; 31]), vec![3u8; 32]);
+        for _ in 0..3 {
+            store
+                .put(leaf.hash(&mut vec![]), encode_node(&leaf).unwrap())
+                .unwrap();
+        }
+        let hash = store_tree(&mut store, &root).unwrap();
+        store.commit(&hash).unwrap();
+        let before = store.size();
+        let count = store.len();
+
+        store.compact().unwrap();
+        assert!(store.size() < before);
+        assert_eq!(store.len(), count);
+        assert_eq!(store.root(), Some(&hash[..]));
+        assert_eq!(load_tree(&store, &hash).unwrap(), root);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(load_tree(&store, &hash).unwrap(), root);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pruning_survives_reopen_and_compaction() {
+        let path = temp_path("prune");
+        let mut history = RootHistory::new(1);
+        let (old, new) = {
+            let mut store = FileStore::open(&path).unwrap();
+            let old = store_tree(&mut store, &tree_with(&[1, 2])).unwrap();
+            store.commit(&old).unwrap();
+            history.commit(&mut store, old.clone()).unwrap();
+            let new = store_tree(&mut store, &tree_with(&[1, 2, 8])).unwrap();
+            store.commit(&new).unwrap();
+            assert!(history.commit(&mut store, new.clone()).unwrap() > 0);
+            (old, new)
+        };
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert!(store.get(&old).unwrap().is_none());
+        assert_eq!(store.roots().to_vec(), vec![new.clone()]);
+        assert_eq!(load_tree(&store, &new).unwrap(), tree_with(&[1, 2, 8]));
+
+        let before = store.size();
+        store.compact().unwrap();
+        assert!(store.size() < before);
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.roots().to_vec(), vec![new.clone()]);
+        assert_eq!(load_tree(&store, &new).unwrap(), tree_with(&[1, 2, 8]));
+        fs::remove_file(&path).unwrap();
+    }
+}
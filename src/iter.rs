@@ -0,0 +1,426 @@
+use super::*;
+
+// An item returned when iterating over a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry<'a> {
+    // A `(key, value)` pair stored in a leaf.
+    Leaf(NibbleKey, &'a [u8]),
+    // A subtree whose content is unknown. The key is the prefix that
+    // is common to all the keys in that subtree, followed by the hash
+    // of the subtree.
+    Hash(NibbleKey, &'a [u8]),
+}
+
+impl<'a> Entry<'a> {
+    pub fn key(&self) -> &NibbleKey {
+        match self {
+            Entry::Leaf(k, _) | Entry::Hash(k, _) => k,
+        }
+    }
+}
+
+// Depth-first iterator over the leaves of a tree, in lexicographic
+// key order. `EmptySlot`s are skipped, and `Hash` nodes are reported
+// as opaque entries.
+pub struct NodeIter<'a> {
+    // Nodes left to visit, along with the key prefix leading to them.
+    // The next node to visit is at the top of the stack.
+    stack: Vec<(Vec<u8>, &'a Node)>,
+}
+
+impl<'a> NodeIter<'a> {
+    fn new(root: &'a Node) -> Self {
+        NodeIter {
+            stack: vec![(vec![], root)],
+        }
+    }
+
+    // Build an iterator over the keys that are greater than or equal
+    // to `start`. The tree is descended along the path of `start`, and
+    // at each level the siblings that come after that path are pushed
+    // on the stack, so that they are visited once the subtree holding
+    // `start` has been exhausted.
+    fn seek(root: &'a Node, start: &[u8]) -> Self {
+        use Node::*;
+
+        let mut stack = Vec::new();
+        let mut prefix = Vec::new();
+        let mut rest = start;
+        let mut node = root;
+        loop {
+            match node {
+                EmptySlot => break,
+                Leaf(leafkey, _) => {
+                    let leafkey: Vec<u8> = leafkey.clone().into();
+                    if &leafkey[..] >= rest {
+                        stack.push((prefix, node));
+                    }
+                    break;
+                }
+                Hash(_, _) => {
+                    // The content of the subtree is unknown, so it
+                    // might hold keys that are greater than `start`.
+                    stack.push((prefix, node));
+                    break;
+                }
+                Extension(ext, child) => {
+                    let common = std::cmp::min(ext.len(), rest.len());
+                    if ext[..common] > rest[..common] {
+                        stack.push((prefix, node));
+                        break;
+                    }
+                    if ext[..common] < rest[..common] {
+                        break;
+                    }
+                    prefix.extend_from_slice(ext);
+                    if rest.len() <= ext.len() {
+                        stack.push((prefix, child));
+                        break;
+                    }
+                    rest = &rest[ext.len()..];
+                    node = child;
+                }
+                FullNode(children) => {
+                    if rest.is_empty() {
+                        stack.push((prefix, node));
+                        break;
+                    }
+                    // A digit beyond the width of the node is greater
+                    // than all of its children, so none of them is kept.
+                    let selector = rest[0] as usize;
+                    if selector >= children.len() {
+                        break;
+                    }
+                    for (i, child) in children.iter().enumerate().skip(selector + 1).rev() {
+                        if *child != EmptySlot {
+                            let mut key = prefix.clone();
+                            key.push(i as u8);
+                            stack.push((key, child));
+                        }
+                    }
+                    prefix.push(rest[0]);
+                    rest = &rest[1..];
+                    node = &children[selector];
+                }
+            }
+        }
+
+        NodeIter { stack }
+    }
+
+    // Build an iterator over the keys starting with `keyprefix`, by
+    // descending directly to the subtree that holds them.
+    fn prefix(root: &'a Node, keyprefix: &[u8]) -> Self {
+        use Node::*;
+
+        let mut stack = Vec::new();
+        let mut prefix = Vec::new();
+        let mut rest = keyprefix;
+        let mut node = root;
+        loop {
+            match node {
+                EmptySlot => break,
+                Leaf(leafkey, _) => {
+                    let leafkey: Vec<u8> = leafkey.clone().into();
+                    if leafkey.starts_with(rest) {
+                        stack.push((prefix, node));
+                    }
+                    break;
+                }
+                Hash(_, _) => {
+                    stack.push((prefix, node));
+                    break;
+                }
+                Extension(ext, child) => {
+                    if rest.len() <= ext.len() {
+                        if ext.starts_with(rest) {
+                            stack.push((prefix, node));
+                        }
+                        break;
+                    }
+                    if !rest.starts_with(ext) {
+                        break;
+                    }
+                    prefix.extend_from_slice(ext);
+                    rest = &rest[ext.len()..];
+                    node = child;
+                }
+                FullNode(children) => {
+                    if rest.is_empty() {
+                        stack.push((prefix, node));
+                        break;
+                    }
+                    // No key can go through a digit beyond the width
+                    // of the node.
+                    match children.get(rest[0] as usize) {
+                        Some(child) => node = child,
+                        None => break,
+                    }
+                    prefix.push(rest[0]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+
+        NodeIter { stack }
+    }
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use Node::*;
+
+        while let Some((prefix, node)) = self.stack.pop() {
+            match node {
+                EmptySlot => {}
+                Leaf(leafkey, value) => {
+                    let mut key = prefix;
+                    key.extend_from_slice(&Into::<Vec<u8>>::into(leafkey.clone()));
+                    return Some(Entry::Leaf(NibbleKey::from_digits_unchecked(key), value));
+                }
+                Hash(h, _) => {
+                    return Some(Entry::Hash(NibbleKey::from_digits_unchecked(prefix), h))
+                }
+                Extension(ext, child) => {
+                    let mut key = prefix;
+                    key.extend_from_slice(ext);
+                    self.stack.push((key, child));
+                }
+                FullNode(children) => {
+                    // Push the children in reverse order, so that the
+                    // one with the smallest index is visited first.
+                    for (i, child) in children.iter().enumerate().rev() {
+                        if *child != EmptySlot {
+                            let mut key = prefix.clone();
+                            key.push(i as u8);
+                            self.stack.push((key, child));
+                        }
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Node {
+    // Iterate over the `(key, value)` pairs of the tree, in key order.
+    pub fn iter(&self) -> NodeIter<'_> {
+        NodeIter::new(self)
+    }
+
+    // Iterate over the `(key, value)` pairs whose key is greater than
+    // or equal to `start`, in key order.
+    pub fn iter_from(&self, start: &[u8]) -> NodeIter<'_> {
+        NodeIter::seek(self, start)
+    }
+
+    // Iterate over the `(key, value)` pairs whose key starts with
+    // `prefix`, in key order.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> NodeIter<'_> {
+        NodeIter::prefix(self, prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Node::*;
+    use super::*;
+
+    fn nibbles(first: u8, rest: u8) -> Vec<u8> {
+        let mut key = vec![rest; 32];
+        key[0] = first;
+        key
+    }
+
+    #[test]
+    fn iterate_empty_tree() {
+        let root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        assert_eq!(root.iter().count(), 0);
+    }
+
+    #[test]
+    fn iterate_in_key_order() {
+        let mut keys = vec![
+            nibbles(8, 1),
+            nibbles(1, 1),
+            nibbles(2, 2),
+            nibbles(1, 0),
+            nibbles(1, 15),
+            vec![3u8; 32],
+        ];
+        keys[5][20] = 7;
+
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        for (i, key) in keys.iter().enumerate() {
+            root = insert_leaf(&mut root, key.clone(), vec![i as u8; 32]).unwrap();
+        }
+
+        let entries: Vec<Entry> = root.iter().collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(entries.len(), keys.len());
+        for (entry, key) in entries.iter().zip(sorted.iter()) {
+            let index = keys.iter().position(|k| k == key).unwrap();
+            assert_eq!(
+                *entry,
+                Entry::Leaf(NibbleKey::new(key.clone()), &[index as u8; 32][..])
+            );
+        }
+    }
+
+    #[test]
+    fn iterate_through_extension() {
+        let root = Extension(
+            vec![0xd, 0xe, 0xa, 0xd],
+            Arc::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32])),
+        );
+        let mut key = vec![0u8; 32];
+        key[..4].copy_from_slice(&[0xd, 0xe, 0xa, 0xd]);
+        assert_eq!(
+            root.iter().collect::<Vec<Entry>>(),
+            vec![Entry::Leaf(NibbleKey::new(key), &[1u8; 32][..])]
+        );
+    }
+
+    #[test]
+    fn iterate_reports_hash_subtrees() {
+        let mut children = vec![EmptySlot; 16];
+        children[2] = Leaf(NibbleKey::new(vec![3u8; 31]), vec![1u8; 32]);
+        children[4] = Hash(vec![5u8; 32], 0);
+        children[9] = Leaf(NibbleKey::new(vec![6u8; 31]), vec![2u8; 32]);
+        let root = FullNode(Arc::new(children));
+
+        let entries: Vec<Entry> = root.iter().collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[1],
+            Entry::Hash(NibbleKey::new(vec![4]), &[5u8; 32][..])
+        );
+        assert_eq!(*entries[2].key(), NibbleKey::new(nibbles(9, 6)));
+    }
+
+    fn sample_keys() -> Vec<Vec<u8>> {
+        let mut keys = vec![];
+        for first in [1u8, 2, 8].iter() {
+            for second in [0u8, 5, 15].iter() {
+                let mut key = vec![*second; 32];
+                key[0] = *first;
+                keys.push(key);
+            }
+        }
+        // Keys sharing a long prefix, to create an extension node
+        let mut key = vec![4u8; 32];
+        key[30] = 1;
+        keys.push(key);
+        keys.push(vec![4u8; 32]);
+        keys.sort();
+        keys
+    }
+
+    fn sample_tree(keys: &[Vec<u8>]) -> Node {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        for key in keys.iter() {
+            insert_leaf(&mut root, key.clone(), key.clone()).unwrap();
+        }
+        root
+    }
+
+    fn collect_keys(iter: NodeIter) -> Vec<Vec<u8>> {
+        iter.map(|e| e.key().clone().into()).collect()
+    }
+
+    #[test]
+    fn iter_from_matches_filtered_scan() {
+        let keys = sample_keys();
+        let root = sample_tree(&keys);
+
+        let mut starts = keys.clone();
+        starts.push(vec![]);
+        starts.push(vec![0]);
+        starts.push(vec![2, 3]);
+        starts.push(vec![4, 4, 4]);
+        starts.push(vec![4, 4, 5]);
+        starts.push(vec![15u8; 32]);
+        let mut before = keys[3].clone();
+        before[31] = 4;
+        starts.push(before);
+
+        for start in starts.iter() {
+            let expected: Vec<Vec<u8>> = keys.iter().filter(|k| k >= &start).cloned().collect();
+            assert_eq!(collect_keys(root.iter_from(start)), expected);
+        }
+    }
+
+    #[test]
+    fn iter_from_out_of_range_digit() {
+        let keys = sample_keys();
+        let root = sample_tree(&keys);
+        assert_eq!(root.iter_from(&[16]).count(), 0);
+        assert_eq!(root.iter_from(&[255, 0]).count(), 0);
+        let expected: Vec<Vec<u8>> = keys.iter().filter(|k| k[0] > 2).cloned().collect();
+        assert_eq!(collect_keys(root.iter_from(&[2, 16])), expected);
+
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 2]));
+        root = insert_leaf(&mut root, vec![0, 1, 1], vec![1]).unwrap();
+        root = insert_leaf(&mut root, vec![1, 0, 1], vec![2]).unwrap();
+        assert_eq!(root.iter_from(&[2]).count(), 0);
+        assert_eq!(collect_keys(root.iter_from(&[0, 2])), vec![vec![1u8, 0, 1]]);
+    }
+
+    #[test]
+    fn iter_prefix_matches_filtered_scan() {
+        let keys = sample_keys();
+        let root = sample_tree(&keys);
+
+        let prefixes = [
+            vec![],
+            vec![1],
+            vec![2, 5],
+            vec![2, 6],
+            vec![3],
+            vec![4, 4, 4],
+            vec![4, 5],
+            keys[2].clone(),
+        ];
+        for prefix in prefixes.iter() {
+            let expected: Vec<Vec<u8>> = keys
+                .iter()
+                .filter(|k| k.starts_with(prefix))
+                .cloned()
+                .collect();
+            assert_eq!(collect_keys(root.iter_prefix(prefix)), expected);
+        }
+    }
+
+    #[test]
+    fn iter_prefix_out_of_range_digit() {
+        let root = sample_tree(&sample_keys());
+        assert_eq!(root.iter_prefix(&[16]).count(), 0);
+        assert_eq!(root.iter_prefix(&[1, 255]).count(), 0);
+
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 2]));
+        root = insert_leaf(&mut root, vec![0, 1, 1], vec![1]).unwrap();
+        root = insert_leaf(&mut root, vec![1, 0, 1], vec![2]).unwrap();
+        assert_eq!(root.iter_prefix(&[2]).count(), 0);
+        assert_eq!(root.iter_prefix(&[1]).count(), 1);
+    }
+
+    #[test]
+    fn iter_prefix_includes_hash_subtrees() {
+        let mut children = vec![EmptySlot; 16];
+        children[4] = Hash(vec![5u8; 32], 0);
+        let root = FullNode(Arc::new(children));
+
+        assert_eq!(
+            root.iter_prefix(&[4, 1]).collect::<Vec<Entry>>(),
+            vec![Entry::Hash(NibbleKey::new(vec![4]), &[5u8; 32][..])]
+        );
+        assert_eq!(root.iter_prefix(&[3]).count(), 0);
+        assert_eq!(root.iter_from(&[4, 1]).count(), 1);
+        assert_eq!(root.iter_from(&[5]).count(), 0);
+    }
+}
@@ -0,0 +1,209 @@
+use super::serde_hex;
+use super::utils::*;
+use super::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io::{Read, Write};
+
+// A readable representation of a `Multiproof`, used to store proofs in
+// fixtures and to review them. Hashes are `0x`-prefixed hex strings,
+// instructions are mnemonics, e.g. "LEAF 31", and the (key, value) pairs
+// are decoded. Unknown fields are rejected, so that the format can evolve
+// without misreading newer files.
+//
+//   {
+//     "hashes": ["0xab..."],
+//     "instructions": ["LEAF 31", "BRANCH 1", "HASHER 0", "ADD 8"],
+//     "keyvals": [{ "key": "0x0101...", "value": "0x0808..." }]
+//   }
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonProof {
+    #[serde(with = "serde_hex::list")]
+    hashes: Vec<Vec<u8>>,
+    #[serde(with = "mnemonics")]
+    instructions: Vec<Instruction>,
+    keyvals: Vec<JsonKeyval>,
+}
+
+// Each digit of the key is written as one byte, as for `NibbleKey`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonKeyval {
+    key: NibbleKey,
+    #[serde(with = "serde_hex::bytes")]
+    value: Vec<u8>,
+}
+
+mod mnemonics {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(
+        instructions: &[Instruction],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(instructions.iter().map(|i| i.to_string()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Instruction>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| s.parse().map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+impl JsonProof {
+    fn from_proof(proof: &Multiproof) -> Result<Self, String> {
+        let mut keyvals = Vec::with_capacity(proof.keyvals.len());
+        for keyval in proof.keyvals.iter() {
+            match rlp::decode::<Node>(keyval) {
+                Ok(Node::Leaf(key, value)) => keyvals.push(JsonKeyval { key, value }),
+                _ => return Err(format!("Invalid (key, value) pair {:?}", keyval)),
+            }
+        }
+        Ok(JsonProof {
+            hashes: proof.hashes.clone(),
+            instructions: proof.instructions.clone(),
+            keyvals,
+        })
+    }
+
+    // The (key, value) pairs are encoded the same way as in the proofs
+    // built by `make_multiproof`.
+    fn into_proof(self) -> Multiproof {
+        Multiproof {
+            hashes: self.hashes,
+            instructions: self.instructions,
+            keyvals: self
+                .keyvals
+                .into_iter()
+                .map(|kv| rlp::encode(&Node::Leaf(kv.key, kv.value)))
+                .collect(),
+        }
+    }
+}
+
+// Write a proof as pretty-printed JSON, so that it can be diffed line by
+// line.
+pub fn to_json(proof: &Multiproof) -> Result<String, String> {
+    serde_json::to_string_pretty(&JsonProof::from_proof(proof)?).map_err(|e| e.to_string())
+}
+
+pub fn from_json(json: &str) -> Result<Multiproof, String> {
+    serde_json::from_str::<JsonProof>(json)
+        .map(JsonProof::into_proof)
+        .map_err(|e| e.to_string())
+}
+
+pub fn write_json<W: Write>(writer: W, proof: &Multiproof) -> Result<(), String> {
+    serde_json::to_writer_pretty(writer, &JsonProof::from_proof(proof)?).map_err(|e| e.to_string())
+}
+
+pub fn read_json<R: Read>(reader: R) -> Result<Multiproof, String> {
+    serde_json::from_reader::<R, JsonProof>(reader)
+        .map(JsonProof::into_proof)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_proof() -> (Node, Multiproof) {
+        let mut root = Node::FullNode(Arc::new(vec![Node::EmptySlot; 16]));
+        root = insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
+        root = insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+        root = insert_leaf(&mut root, [1u8, 2].repeat(16), vec![2u8; 32]).unwrap();
+        let proof = make_multiproof(
+            &root,
+            vec![
+                (vec![1u8; 32], vec![1u8; 32]),
+                ([1u8, 2].repeat(16), vec![2u8; 32]),
+            ],
+        )
+        .unwrap();
+        (root, proof)
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let (root, proof) = sample_proof();
+        let json = to_json(&proof).unwrap();
+        let decoded = from_json(&json).unwrap();
+        assert_eq!(decoded, proof);
+        verify_multiproof(&root.hash(&mut vec![]), &decoded).unwrap();
+
+        let mut buffer = Vec::new();
+        write_json(&mut buffer, &proof).unwrap();
+        assert_eq!(String::from_utf8(buffer.clone()).unwrap(), json);
+        assert_eq!(read_json(&buffer[..]).unwrap(), proof);
+    }
+
+    #[test]
+    fn json_layout() {
+        let proof = Multiproof {
+            hashes: vec![vec![0xab, 0xcd]],
+            instructions: vec![
+                Instruction::LEAF(1),
+                Instruction::EXTENSION(vec![1, 2]),
+                Instruction::HASHER(0),
+                Instruction::ADD(3),
+            ],
+            keyvals: vec![rlp::encode(&Node::Leaf(
+                NibbleKey::new(vec![3]),
+                vec![4, 5],
+            ))],
+        };
+        let value: serde_json::Value = serde_json::from_str(&to_json(&proof).unwrap()).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "hashes": ["0xabcd"],
+                "instructions": ["LEAF 1", "EXTENSION 0102", "HASHER 0", "ADD 3"],
+                "keyvals": [{ "key": "0x03", "value": "0x0405" }],
+            })
+        );
+    }
+
+    #[test]
+    fn empty_extension_round_trip() {
+        let proof = Multiproof {
+            hashes: vec![],
+            instructions: vec![Instruction::LEAF(0), Instruction::EXTENSION(vec![])],
+            keyvals: vec![rlp::encode(&Node::Leaf(
+                NibbleKey::new(vec![3]),
+                vec![4, 5],
+            ))],
+        };
+        let json = to_json(&proof).unwrap();
+        assert!(json.contains("\"EXTENSION -\""), "{}", json);
+        assert_eq!(from_json(&json).unwrap(), proof);
+    }
+
+    #[test]
+    fn invalid_json() {
+        let valid = r#"{"hashes": [], "instructions": ["LEAF 0"], "keyvals": [{"key": "0x", "value": "0x01"}]}"#;
+        assert!(from_json(valid).is_ok());
+
+        let invalid = [
+            r#"{"hashes": [], "instructions": ["LEAF"], "keyvals": []}"#,
+            r#"{"hashes": ["0x1"], "instructions": [], "keyvals": []}"#,
+            r#"{"hashes": [], "instructions": [], "keyvals": [{"key": "0x"}]}"#,
+            r#"{"hashes": [], "instructions": [], "keyvals": [], "extra": 1}"#,
+            r#"{"hashes": [], "instructions": []}"#,
+        ];
+        for json in invalid.iter() {
+            assert!(from_json(json).is_err(), "{}", json);
+        }
+
+        let proof = Multiproof {
+            hashes: vec![],
+            instructions: vec![],
+            keyvals: vec![vec![0x01]],
+        };
+        assert!(to_json(&proof).is_err());
+    }
+}
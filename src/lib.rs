@@ -1,19 +1,82 @@
-#![feature(box_syntax, box_patterns)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(not(feature = "std"))]
+extern crate alloc;
 extern crate rlp;
 extern crate sha3;
 
+// Only the tree, proof and verification code is available without the
+// `std` feature, so that proofs can be checked in constrained environments.
+#[cfg(feature = "std")]
+pub mod account;
+#[cfg(feature = "std")]
+pub mod asm;
+#[cfg(feature = "std")]
+pub mod binary;
+#[cfg(feature = "std")]
+pub mod code_chunks;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "std")]
+pub mod file_store;
+#[cfg(feature = "std")]
+pub mod iter;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "std")]
+pub mod secure_trie;
+#[cfg(feature = "serde")]
+pub mod serde_hex;
+#[cfg(feature = "std")]
+pub mod smt;
+#[cfg(feature = "std")]
+pub mod ssz;
+#[cfg(feature = "std")]
+pub mod stack_trie;
+#[cfg(feature = "std")]
+pub mod state_proof;
+#[cfg(feature = "std")]
+pub mod store;
+#[cfg(feature = "std")]
+pub mod transition;
+#[cfg(feature = "std")]
+pub mod trie;
 pub mod utils;
-
+#[cfg(feature = "std")]
+pub mod world_state;
+
+#[cfg(not(feature = "std"))]
+use alloc::{
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
 use sha3::{Digest, Keccak256};
+#[cfg(feature = "std")]
+use std::sync::Arc;
 use utils::*;
 
+// Children are reference-counted, so that cloning a node is cheap and
+// updating a tree only copies the nodes along the modified path. The
+// previous root remains valid, and can be kept as a snapshot.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Node {
-    Hash(Vec<u8>, usize), // (Hash, # empty spaces)
-    Leaf(NibbleKey, Vec<u8>),
-    Extension(Vec<u8>, Box<Node>),
-    FullNode(Vec<Node>),
+    Hash(
+        #[cfg_attr(feature = "serde", serde(with = "serde_hex::bytes"))] Vec<u8>,
+        usize,
+    ), // (Hash, # empty spaces)
+    Leaf(
+        NibbleKey,
+        #[cfg_attr(feature = "serde", serde(with = "serde_hex::bytes"))] Vec<u8>,
+    ),
+    Extension(
+        #[cfg_attr(feature = "serde", serde(with = "serde_hex::bytes"))] Vec<u8>,
+        Arc<Node>,
+    ),
+    FullNode(Arc<Vec<Node>>),
     EmptySlot,
 }
 
@@ -34,92 +97,220 @@ impl rlp::Decodable for Node {
             return Err(rlp::DecoderError::RlpExpectedToBeList);
         }
         let keyval = rlp.as_list::<Vec<u8>>()?;
+        if keyval.len() != 2 {
+            return Err(rlp::DecoderError::RlpIncorrectListLen);
+        }
         Ok(Node::Leaf(
-            NibbleKey::new(keyval[0].clone()),
+            NibbleKey::from_digits_unchecked(keyval[0].clone()),
             keyval[1].clone(),
         ))
     }
 }
 
+// Hash `encoding` if it is more than 32 bytes long. The hasher is taken
+// from the `hashers` pool, and put back into it once it has been reset.
+fn hash_encoding(encoding: Vec<u8>, hashers: &mut Vec<Keccak256>) -> Vec<u8> {
+    if encoding.len() <= 32 {
+        return encoding;
+    }
+    let mut hasher = hashers.pop().unwrap_or_default();
+    hasher.input(&encoding);
+    let hash = Vec::<u8>::from(&hasher.result_reset()[..]);
+    hashers.push(hasher);
+    hash
+}
+
 impl Node {
-    fn hash(&self, hashers: &mut Vec<Keccak256>) -> Vec<u8> {
+    // `hashers` is a pool of hashers that are reused across nodes, so
+    // that callers hashing many nodes can avoid creating a hasher for
+    // each of them.
+    pub fn hash(&self, hashers: &mut Vec<Keccak256>) -> Vec<u8> {
         use Node::*;
         match self {
             EmptySlot => Vec::new(),
-            Leaf(_, _) => {
-                let encoding = rlp::encode(self);
-
-                // Only hash if the encoder output is less than 32 bytes.
-                if encoding.len() > 32 {
-                    let mut hasher = Keccak256::new();
-                    hasher.input(&encoding);
-                    Vec::<u8>::from(&hasher.result()[..])
-                } else {
-                    encoding
-                }
-            }
+            Leaf(_, _) => hash_encoding(rlp::encode(self), hashers),
             Extension(ref ext, node) => {
                 let subtree_hash = node.hash(hashers);
-                let encoding =
-                    rlp::encode_list::<Vec<u8>, Vec<u8>>(&vec![ext.clone(), subtree_hash.clone()]);
-
-                // Only hash if the encoder output is less than 32 bytes.
-                if encoding.len() > 32 {
-                    let mut hasher = Keccak256::new();
-                    hasher.input(&encoding);
-                    Vec::<u8>::from(&hasher.result()[..])
-                } else {
-                    encoding
-                }
+                let encoding = rlp::encode_list::<Vec<u8>, Vec<u8>>(&[ext.clone(), subtree_hash]);
+                hash_encoding(encoding, hashers)
             }
             FullNode(ref nodes) => {
                 let mut keys = Vec::new();
-                for node in nodes {
+                for node in nodes.iter() {
                     keys.push(node.hash(hashers));
                 }
-                let encoding = rlp::encode_list::<Vec<u8>, Vec<u8>>(&keys[..]);
-
-                // Only hash if the encoder output is less than 32 bytes.
-                if encoding.len() > 32 {
-                    let mut hasher = Keccak256::new();
-                    hasher.input(&encoding);
-                    Vec::<u8>::from(&hasher.result()[..])
-                } else {
-                    encoding
-                }
+                hash_encoding(rlp::encode_list::<Vec<u8>, Vec<u8>>(&keys[..]), hashers)
             }
             Hash(h, _) => h.to_vec(),
         }
     }
 }
 
-#[derive(Debug)]
+// The number of children of a full node in a hexary tree. Trees of any
+// width up to `MAX_WIDTH` can be built by starting from a root full node
+// with that many children.
+pub const DEFAULT_WIDTH: usize = 16;
+
+// Find the width of a tree from its topmost full node.
+fn width_of(node: &Node) -> usize {
+    match node {
+        Node::FullNode(children) => children.len(),
+        Node::Extension(_, child) => width_of(child),
+        _ => DEFAULT_WIDTH,
+    }
+}
+
+fn check_digits(key: &[u8], width: usize) -> Result<(), String> {
+    if key.iter().any(|d| *d as usize >= width) {
+        return Err(format!(
+            "Invalid digit in key {:?} for width {}",
+            key, width
+        ));
+    }
+    Ok(())
+}
+
+// A `Resolver` is used to load the content of a `Hash` node when a
+// tree traversal reaches it, e.g. from storage.
+pub trait Resolver {
+    // Return the node whose hash is `hash`. Its children can themselves
+    // be `Hash` nodes, which will be resolved if they are reached.
+    fn resolve(&self, hash: &[u8]) -> Result<Node, String>;
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&[u8]) -> Result<Node, String>,
+{
+    fn resolve(&self, hash: &[u8]) -> Result<Node, String> {
+        self(hash)
+    }
+}
+
+// A resolver that fails on every hash, for trees that are expected to
+// be complete.
+pub struct NoResolver;
+
+impl Resolver for NoResolver {
+    fn resolve(&self, hash: &[u8]) -> Result<Node, String> {
+        Err(format!("Could not resolve hash {:?}", hash))
+    }
+}
+
+// Resolve `hash` and make sure that the returned node does hash to it.
+fn resolve_hash<R: Resolver>(resolver: &R, hash: &[u8]) -> Result<Node, String> {
+    let node = resolver.resolve(hash)?;
+    if node.hash(&mut vec![]) != hash {
+        return Err(format!("Resolved node does not match hash {:?}", hash));
+    }
+    Ok(node)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     BRANCH(usize),
     HASHER(usize),
     LEAF(usize),
-    EXTENSION(Vec<u8>),
+    EXTENSION(#[cfg_attr(feature = "serde", serde(with = "serde_hex::bytes"))] Vec<u8>),
     ADD(usize),
 }
 
-#[derive(Debug)]
+// Instructions are written as their mnemonic followed by their argument,
+// e.g. `LEAF 31`. The digits of an `EXTENSION` are written in hex, one
+// byte per digit, e.g. `EXTENSION 0a0b`.
+impl core::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        use Instruction::*;
+        match self {
+            BRANCH(n) => write!(f, "BRANCH {}", n),
+            HASHER(n) => write!(f, "HASHER {}", n),
+            LEAF(n) => write!(f, "LEAF {}", n),
+            ADD(n) => write!(f, "ADD {}", n),
+            // An empty extension is written `EXTENSION -`, so that the
+            // instruction always has an argument.
+            EXTENSION(digits) if digits.is_empty() => write!(f, "EXTENSION -"),
+            EXTENSION(digits) => {
+                write!(f, "EXTENSION ")?;
+                for d in digits.iter() {
+                    write!(f, "{:02x}", d)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl core::str::FromStr for Instruction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        use Instruction::*;
+        let mut tokens = s.split_whitespace();
+        let (mnemonic, arg) = match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some(mnemonic), Some(arg), None) => (mnemonic, arg),
+            _ => return Err(format!("Expected a mnemonic and an argument in {:?}", s)),
+        };
+        let number = || {
+            arg.parse::<usize>()
+                .map_err(|e| format!("Invalid argument in {:?}: {}", s, e))
+        };
+        match mnemonic {
+            "BRANCH" => Ok(BRANCH(number()?)),
+            "HASHER" => Ok(HASHER(number()?)),
+            "LEAF" => Ok(LEAF(number()?)),
+            "ADD" => Ok(ADD(number()?)),
+            "EXTENSION" if arg == "-" => Ok(EXTENSION(vec![])),
+            "EXTENSION" => {
+                if !arg.len().is_multiple_of(2) || !arg.bytes().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("Invalid extension digits in {:?}", s));
+                }
+                let mut digits = Vec::with_capacity(arg.len() / 2);
+                for i in (0..arg.len()).step_by(2) {
+                    let digit = u8::from_str_radix(&arg[i..i + 2], 16)
+                        .map_err(|e| format!("Invalid extension digits in {:?}: {}", s, e))?;
+                    digits.push(digit);
+                }
+                Ok(EXTENSION(digits))
+            }
+            _ => Err(format!("Unknown instruction {:?}", mnemonic)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Multiproof {
-    pub hashes: Vec<Vec<u8>>,           // List of hashes in the proof
+    #[cfg_attr(feature = "serde", serde(with = "serde_hex::list"))]
+    pub hashes: Vec<Vec<u8>>, // List of hashes in the proof
     pub instructions: Vec<Instruction>, // List of instructions in the proof
-    pub keyvals: Vec<Vec<u8>>,          // List of RLP-encoded (key, value) pairs in the proof
+    #[cfg_attr(feature = "serde", serde(with = "serde_hex::list"))]
+    pub keyvals: Vec<Vec<u8>>, // List of RLP-encoded (key, value) pairs in the proof
 }
 
-// Rebuilds the tree based on the multiproof components
-pub fn rebuild(stack: &mut Vec<Node>, proof: &Multiproof) -> Node {
+// Rebuilds the tree based on the multiproof components, returning an
+// error if the proof is malformed.
+pub fn try_rebuild(stack: &mut Vec<Node>, proof: &Multiproof) -> Result<Node, String> {
+    try_rebuild_with_width(stack, proof, DEFAULT_WIDTH)
+}
+
+// Rebuilds a tree whose full nodes have `width` children, returning an
+// error if the proof is malformed.
+pub fn try_rebuild_with_width(
+    stack: &mut Vec<Node>,
+    proof: &Multiproof,
+    width: usize,
+) -> Result<Node, String> {
     use Instruction::*;
     use Node::*;
 
     let mut hiter = proof.hashes.iter();
     let iiter = proof.instructions.iter();
-    let mut kviter = proof.keyvals.iter().map(|encoded| {
-        // Deserialize the keys as they are read
-        rlp::decode::<Node>(encoded).unwrap()
-    });
+    // Deserialize the keys as they are read
+    let mut kviter = proof
+        .keyvals
+        .iter()
+        .map(|encoded| rlp::decode::<Node>(encoded).map_err(|e| format!("{:?}", e)));
 
     for instr in iiter {
         match instr {
@@ -127,30 +318,52 @@ pub fn rebuild(stack: &mut Vec<Node>, proof: &Multiproof) -> Node {
                 if let Some(h) = hiter.next() {
                     stack.push(Hash(h.to_vec(), *digit));
                 } else {
-                    panic!("Proof requires one more hash in HASHER")
+                    return Err("Proof requires one more hash in HASHER".to_string());
                 }
             }
-            LEAF(keylength) => {
-                if let Some(Leaf(key, value)) = kviter.next() {
+            LEAF(keylength) => match kviter.next() {
+                Some(Ok(Leaf(key, value))) => {
+                    if *keylength > key.as_ref().len() {
+                        return Err(format!(
+                            "Invalid key length in LEAF: {} > {}",
+                            keylength,
+                            key.as_ref().len()
+                        ));
+                    }
+                    check_digits(key.as_ref(), width)?;
                     stack.push(Leaf(key.keep_suffix(*keylength), value.to_vec()));
-                } else {
-                    panic!("Proof requires one more (key,value) pair in LEAF");
                 }
-            }
+                Some(Err(e)) => return Err(format!("Invalid (key,value) pair in LEAF: {}", e)),
+                _ => return Err("Proof requires one more (key,value) pair in LEAF".to_string()),
+            },
             BRANCH(digit) => {
+                if *digit >= width {
+                    return Err(format!(
+                        "Incorrect full node index: {} > {}",
+                        digit,
+                        width - 1
+                    ));
+                }
                 if let Some(node) = stack.pop() {
-                    let mut children = vec![Node::EmptySlot; 16];
+                    let mut children = vec![Node::EmptySlot; width];
                     children[*digit] = node;
-                    stack.push(FullNode(children))
+                    stack.push(FullNode(Arc::new(children)))
                 } else {
-                    panic!("Could not pop a value from the stack, that is required for a BRANCH")
+                    return Err(
+                        "Could not pop a value from the stack, that is required for a BRANCH"
+                            .to_string(),
+                    );
                 }
             }
             EXTENSION(key) => {
+                check_digits(key, width)?;
                 if let Some(node) = stack.pop() {
-                    stack.push(Extension(key.to_vec(), Box::new(node)));
+                    stack.push(Extension(key.to_vec(), Arc::new(node)));
                 } else {
-                    panic!("Could not find a node on the stack, that is required for an EXTENSION")
+                    return Err(
+                        "Could not find a node on the stack, that is required for an EXTENSION"
+                            .to_string(),
+                    );
                 }
             }
             ADD(digit) => {
@@ -158,28 +371,85 @@ pub fn rebuild(stack: &mut Vec<Node>, proof: &Multiproof) -> Node {
                     match el2 {
                         FullNode(ref mut n2) => {
                             if *digit >= n2.len() {
-                                panic!(format!(
+                                return Err(format!(
                                     "Incorrect full node index: {} > {}",
                                     digit,
                                     n2.len() - 1
-                                ))
+                                ));
                             }
 
                             // A hash needs to be fed into the hash sponge, any other node is simply
                             // a child (el1) of the parent node (el2). this is done during resolve.
-                            n2[*digit] = el1;
+                            Arc::make_mut(n2)[*digit] = el1;
+                        }
+                        Hash(_, _) => {
+                            return Err("Hash node no longer supported in this case".to_string())
                         }
-                        Hash(_, _) => panic!("Hash node no longer supported in this case"),
-                        _ => panic!("Unexpected node type"),
+                        _ => return Err("Unexpected node type".to_string()),
                     }
                 } else {
-                    panic!("Could not find enough parameters to ADD")
+                    return Err("Could not find enough parameters to ADD".to_string());
                 }
             }
         }
     }
 
-    stack.pop().unwrap()
+    stack
+        .pop()
+        .ok_or_else(|| "Proof did not produce any node".to_string())
+}
+
+// Rebuilds the tree based on the multiproof components. It panics if
+// the proof is malformed, see `try_rebuild` for a version that doesn't.
+pub fn rebuild(stack: &mut Vec<Node>, proof: &Multiproof) -> Node {
+    match try_rebuild(stack, proof) {
+        Ok(node) => node,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+// Rebuilds the tree based on the multiproof components, and checks that
+// its hash is `root_hash`. The returned tree can then be queried for the
+// proven values.
+pub fn verify_multiproof(root_hash: &[u8], proof: &Multiproof) -> Result<Node, String> {
+    verify_multiproof_with_width(root_hash, proof, DEFAULT_WIDTH)
+}
+
+// Same as `verify_multiproof`, for a tree whose full nodes have `width`
+// children.
+pub fn verify_multiproof_with_width(
+    root_hash: &[u8],
+    proof: &Multiproof,
+    width: usize,
+) -> Result<Node, String> {
+    let root = try_rebuild_with_width(&mut vec![], proof, width)?;
+    let hash = root.hash(&mut vec![]);
+    if hash != root_hash {
+        return Err(format!(
+            "Invalid proof: expected root hash {:?}, got {:?}",
+            root_hash, hash
+        ));
+    }
+    Ok(root)
+}
+
+// The proof-checking API shared by the different kinds of trees: a
+// proof is checked against the root hash of a tree, and what it proves
+// is returned, e.g. the rebuilt tree or the list of proven values.
+pub trait VerifyMultiproof {
+    type Proof;
+    type Proven;
+
+    fn verify_multiproof(root_hash: &[u8], proof: &Self::Proof) -> Result<Self::Proven, String>;
+}
+
+impl VerifyMultiproof for Node {
+    type Proof = Multiproof;
+    type Proven = Node;
+
+    fn verify_multiproof(root_hash: &[u8], proof: &Multiproof) -> Result<Node, String> {
+        verify_multiproof(root_hash, proof)
+    }
 }
 
 // Utility function to find the length of the common prefix of two keys
@@ -192,7 +462,7 @@ fn find_common_length(s1: &[u8], s2: &[u8]) -> usize {
     let mut firstdiffindex = shortest.len();
     for (i, &n) in shortest.iter().enumerate() {
         if n != longuest[i] {
-            firstdiffindex = i as usize;
+            firstdiffindex = i;
             break;
         }
     }
@@ -200,12 +470,81 @@ fn find_common_length(s1: &[u8], s2: &[u8]) -> usize {
     firstdiffindex
 }
 
+// Get the value associated to `key` in the (sub-)tree represented
+// by `root`, resolving `Hash` nodes with `resolver`.
+pub fn get_with_resolver<R: Resolver>(
+    root: &Node,
+    key: &[u8],
+    resolver: &R,
+) -> Result<Option<Vec<u8>>, String> {
+    use Node::*;
+
+    match root {
+        EmptySlot => Ok(None),
+        Leaf(leafkey, value) => {
+            if leafkey.as_ref() == key {
+                Ok(Some(value.to_vec()))
+            } else {
+                Ok(None)
+            }
+        }
+        Extension(extkey, child) => {
+            if key.len() >= extkey.len() && key[..extkey.len()] == extkey[..] {
+                get_with_resolver(child, &key[extkey.len()..], resolver)
+            } else {
+                Ok(None)
+            }
+        }
+        FullNode(ref vec) => {
+            if key.is_empty() {
+                return Ok(None);
+            }
+            match vec.get(key[0] as usize) {
+                Some(child) => get_with_resolver(child, &key[1..], resolver),
+                None => Err(format!("Invalid digit in key {:?}", key)),
+            }
+        }
+        Hash(h, _) => get_with_resolver(&resolve_hash(resolver, h)?, key, resolver),
+    }
+}
+
+// Get the value associated to `key` in a complete (sub-)tree.
+pub fn get(root: &Node, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    get_with_resolver(root, key, &NoResolver)
+}
+
 // Insert a `(key,value)` pair into a (sub-)tree represented by `root`.
 // It returns the root of the updated (sub-)tree.
 pub fn insert_leaf(root: &mut Node, key: Vec<u8>, value: Vec<u8>) -> Result<Node, String> {
+    insert_leaf_with_resolver(root, key, value, &NoResolver)
+}
+
+// Insert a `(key,value)` pair into a (sub-)tree represented by `root`,
+// resolving the `Hash` nodes that are found along the path of `key`
+// with `resolver`. It returns the root of the updated (sub-)tree.
+pub fn insert_leaf_with_resolver<R: Resolver>(
+    root: &mut Node,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    resolver: &R,
+) -> Result<Node, String> {
+    // The digits of the key must select a child in the full nodes of
+    // the tree, whose width is that of the topmost one.
+    let width = width_of(root);
+    check_digits(&key, width)?;
+    insert_node(root, key, value, width, resolver)
+}
+
+fn insert_node<R: Resolver>(
+    root: &mut Node,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    width: usize,
+    resolver: &R,
+) -> Result<Node, String> {
     use Node::*;
 
-    if key.len() == 0 {
+    if key.is_empty() {
         return Err("Attempted to insert a 0-byte key".to_string());
     }
 
@@ -213,116 +552,262 @@ pub fn insert_leaf(root: &mut Node, key: Vec<u8>, value: Vec<u8>) -> Result<Node
         Leaf(leafkey, leafvalue) => {
             // Find the common part of the current key with that of the
             // leaf and create an intermediate full node.
-            let firstdiffindex = leafkey.factor_length(&NibbleKey::new(key.clone()));
+            let firstdiffindex = leafkey.factor_length(&NibbleKey::with_width(key.clone(), width));
 
-            // Return an error if the leaf is already present.
-            if firstdiffindex == key.len() {
-                return Err(format!("Key is is already present!",));
+            // Return an error if the leaf is already present, or if
+            // one of the keys is a prefix of the other.
+            if firstdiffindex == key.len() && firstdiffindex == leafkey.as_ref().len() {
+                return Err("Key is is already present!".to_string());
+            }
+            if firstdiffindex == key.len() || firstdiffindex == leafkey.as_ref().len() {
+                return Err(format!(
+                    "Key {:?} and leaf key {:?} are prefixes of one another",
+                    key, leafkey
+                ));
             }
 
             // Create the new root, which is a full node.
-            let mut res = vec![EmptySlot; 16];
+            let mut res = vec![EmptySlot; width];
             // Add the initial leaf, with a key truncated by the common
             // key part.
             res[leafkey[firstdiffindex] as usize] =
                 Leaf(leafkey.remove_prefix(firstdiffindex), leafvalue.to_vec());
             // Add the node to be inserted
-            res[key[firstdiffindex] as usize] =
-                Leaf(NibbleKey::new(key[firstdiffindex + 1..].to_vec()), value);
+            res[key[firstdiffindex] as usize] = Leaf(
+                NibbleKey::with_width(key[firstdiffindex + 1..].to_vec(), width),
+                value,
+            );
             // Put the common part into an extension node
             if firstdiffindex == 0 {
                 // Special case: no extension necessary
-                Ok(FullNode(res))
+                Ok(FullNode(Arc::new(res)))
             } else {
                 Ok(Extension(
                     key[..firstdiffindex].to_vec(),
-                    Box::new(FullNode(res)),
+                    Arc::new(FullNode(Arc::new(res))),
                 ))
             }
         }
-        Extension(extkey, box child) => {
+        Extension(extkey, child) => {
             // Find the common part of the current key with that of the
             // extension and create an intermediate full node.
-            let firstdiffindex = find_common_length(&key, &extkey);
+            let firstdiffindex = find_common_length(&key, extkey);
 
             assert!(firstdiffindex <= extkey.len());
             assert!(firstdiffindex <= key.len());
 
+            // The key stops within the extension, so it is a prefix of
+            // the keys that are already present under it.
+            if firstdiffindex == key.len() {
+                return Err(format!(
+                    "Key {:?} is a prefix of extension {:?}",
+                    key, extkey
+                ));
+            }
+
             // Special case: key is longer than the extension key:
             // recurse on the child node.
             if firstdiffindex == extkey.len() {
-                let childroot =
-                    insert_leaf(&mut child.clone(), key[extkey.len()..].to_vec(), value)?;
-                return Ok(Extension(extkey.to_vec(), Box::new(childroot)));
+                let childroot = insert_node(
+                    &mut Node::clone(child),
+                    key[extkey.len()..].to_vec(),
+                    value,
+                    width,
+                    resolver,
+                )?;
+                return Ok(Extension(extkey.to_vec(), Arc::new(childroot)));
             }
 
             // Special case: key is completely unlike the extension key
             if firstdiffindex == 0 {
-                let mut res = vec![EmptySlot; 16];
+                let mut res = vec![EmptySlot; width];
 
                 // Create the entry for the truncated extension key
                 // Was it an extension of 1 ? If so, place the node directly
                 // otherwise truncate the extension.
                 res[extkey[0] as usize] = if extkey.len() == 1 {
-                    child.clone()
+                    Node::clone(child)
                 } else {
-                    Extension(extkey[1..].to_vec(), Box::new(child.clone()))
+                    Extension(extkey[1..].to_vec(), child.clone())
                 };
 
                 // Create the entry for the node. If there was only a
                 // difference of one byte, that byte will be consumed by
                 // the fullnode and therefore the key in the leaf will be
                 // an empty slice `[]`.
-                res[key[0] as usize] = Leaf(NibbleKey::new(key[1..].to_vec()), value);
+                res[key[0] as usize] = Leaf(NibbleKey::with_width(key[1..].to_vec(), width), value);
 
-                return Ok(FullNode(res));
+                return Ok(FullNode(Arc::new(res)));
             }
 
             // Create the new root, which is a full node.
-            let mut res = vec![EmptySlot; 16];
+            let mut res = vec![EmptySlot; width];
             // Add the initial leaf, with a key truncated by the common
             // key part. If the common part corresponds to the extension
             // key length minus one, then there is no need for the creation
             // of an extension node past the full node.
             res[extkey[firstdiffindex] as usize] = if extkey.len() - firstdiffindex > 1 {
-                Extension(
-                    extkey[firstdiffindex + 1..].to_vec(),
-                    Box::new(child.clone()),
-                )
+                Extension(extkey[firstdiffindex + 1..].to_vec(), child.clone())
             } else {
-                child.clone()
+                Node::clone(child)
             };
             // Add the node to be inserted
-            res[key[firstdiffindex] as usize] =
-                Leaf(NibbleKey::new(key[firstdiffindex + 1..].to_vec()), value);
+            res[key[firstdiffindex] as usize] = Leaf(
+                NibbleKey::with_width(key[firstdiffindex + 1..].to_vec(), width),
+                value,
+            );
             // Put the common part into an extension node
             Ok(Extension(
                 extkey[..firstdiffindex].to_vec(),
-                Box::new(FullNode(res)),
+                Arc::new(FullNode(Arc::new(res))),
             ))
         }
         FullNode(ref mut vec) => {
             let idx = key[0] as usize;
+            if idx >= vec.len() {
+                return Err(format!("Invalid digit in key {:?}", key));
+            }
+            // Only copy the list of children if it is shared with
+            // another tree.
+            let children = Arc::make_mut(vec);
             // If the slot isn't yet in use, fill it, and otherwise,
             // recurse into the child node.
-            vec[idx] = if vec[idx] == EmptySlot {
+            children[idx] = if children[idx] == EmptySlot {
                 // XXX check that the value is at least 1
-                Leaf(NibbleKey::new(key[1..].to_vec()), value)
+                Leaf(NibbleKey::with_width(key[1..].to_vec(), width), value)
             } else {
-                insert_leaf(&mut vec[idx], key[idx + 1..].to_vec(), value)?
+                insert_node(
+                    &mut children[idx],
+                    key[1..].to_vec(),
+                    value,
+                    width,
+                    resolver,
+                )?
             };
             // Return the root node with an updated entry
-            Ok(FullNode(vec.to_vec()))
+            Ok(FullNode(vec.clone()))
+        }
+        Hash(h, _) => {
+            // Replace the hash with the actual subtree and insert into it
+            *root = resolve_hash(resolver, h)?;
+            insert_node(root, key, value, width, resolver)
         }
-        _ => panic!("Not supported yet"),
+        EmptySlot => Ok(Leaf(NibbleKey::with_width(key, width), value)),
     }
 }
 
+// Prepend `nibbles` to the key of a node that is the only child left
+// in its parent full node, or in its parent extension, so that it can
+// replace that parent.
+fn merge_prefix<R: Resolver>(nibbles: &[u8], node: Node, resolver: &R) -> Result<Node, String> {
+    use Node::*;
+
+    match node {
+        Leaf(leafkey, value) => {
+            let mut key = nibbles.to_vec();
+            key.extend_from_slice(&Into::<Vec<u8>>::into(leafkey));
+            Ok(Leaf(NibbleKey::from_digits_unchecked(key), value))
+        }
+        Extension(extkey, child) => {
+            let mut key = nibbles.to_vec();
+            key.extend_from_slice(&extkey);
+            Ok(Extension(key, child))
+        }
+        FullNode(_) => Ok(Extension(nibbles.to_vec(), Arc::new(node))),
+        // The type of the node is needed to know how to merge it
+        Hash(h, _) => merge_prefix(nibbles, resolve_hash(resolver, &h)?, resolver),
+        EmptySlot => Ok(EmptySlot),
+    }
+}
+
+fn remove_node<R: Resolver>(
+    node: &Node,
+    key: &[u8],
+    is_root: bool,
+    resolver: &R,
+) -> Result<Node, String> {
+    use Node::*;
+
+    match node {
+        EmptySlot => Err(format!("Key {:?} isn't present in the tree", key)),
+        Leaf(leafkey, _) => {
+            if leafkey.as_ref() == key {
+                Ok(EmptySlot)
+            } else {
+                Err(format!("Key {:?} isn't present in the tree", key))
+            }
+        }
+        Extension(extkey, child) => {
+            if key.len() <= extkey.len() || key[..extkey.len()] != extkey[..] {
+                return Err(format!("Key {:?} isn't present in the tree", key));
+            }
+            let newchild = remove_node(child, &key[extkey.len()..], false, resolver)?;
+            merge_prefix(extkey, newchild, resolver)
+        }
+        FullNode(children) => {
+            if key.is_empty() {
+                return Err("Key is too short".to_string());
+            }
+            let idx = key[0] as usize;
+            let child = children
+                .get(idx)
+                .ok_or_else(|| format!("Invalid digit in key {:?}", key))?;
+            let newchild = remove_node(child, &key[1..], false, resolver)?;
+            let mut newchildren = children.to_vec();
+            newchildren[idx] = newchild;
+
+            // The root node is always a full node. Otherwise, a full node
+            // that is left with a single child gets replaced by it.
+            let remaining: Vec<usize> = (0..newchildren.len())
+                .filter(|i| newchildren[*i] != EmptySlot)
+                .collect();
+            match remaining.len() {
+                _ if is_root => Ok(FullNode(Arc::new(newchildren))),
+                0 => Ok(EmptySlot),
+                1 => {
+                    let i = remaining[0];
+                    let child = core::mem::replace(&mut newchildren[i], EmptySlot);
+                    merge_prefix(&[i as u8], child, resolver)
+                }
+                _ => Ok(FullNode(Arc::new(newchildren))),
+            }
+        }
+        Hash(h, _) => remove_node(&resolve_hash(resolver, h)?, key, is_root, resolver),
+    }
+}
+
+// Remove `key` from the tree represented by `root`, resolving `Hash`
+// nodes with `resolver`. It returns the root of the updated tree, whose
+// structure is the same as if `key` had never been inserted.
+pub fn remove_leaf_with_resolver<R: Resolver>(
+    root: &Node,
+    key: &[u8],
+    resolver: &R,
+) -> Result<Node, String> {
+    remove_node(root, key, true, resolver)
+}
+
+// Remove `key` from the tree represented by `root`, and return the root
+// of the updated tree.
+pub fn remove_leaf(root: &Node, key: &[u8]) -> Result<Node, String> {
+    remove_leaf_with_resolver(root, key, &NoResolver)
+}
+
 // Helper function that generates a multiproof based on one `(key.value)`
 // pair.
 pub fn make_multiproof(
     root: &Node,
     keyvals: Vec<(Vec<u8>, Vec<u8>)>,
+) -> Result<Multiproof, String> {
+    make_multiproof_with_resolver(root, keyvals, &NoResolver)
+}
+
+// Same as `make_multiproof`, except that `Hash` nodes that are found
+// along the path of the keys are resolved with `resolver`.
+pub fn make_multiproof_with_resolver<R: Resolver>(
+    root: &Node,
+    keyvals: Vec<(Vec<u8>, Vec<u8>)>,
+    resolver: &R,
 ) -> Result<Multiproof, String> {
     use Node::*;
 
@@ -332,7 +817,7 @@ pub fn make_multiproof(
 
     // If there are no keys specified at this node, then just hash that
     // node.
-    if keyvals.len() == 0 {
+    if keyvals.is_empty() {
         return Ok(Multiproof {
             instructions: vec![Instruction::HASHER(0)],
             hashes: vec![root.hash(&mut vec![])],
@@ -346,8 +831,11 @@ pub fn make_multiproof(
         FullNode(ref vec) => {
             // Split the current (key,value) tuples based on the first
             // nibble of their keys. Build a recursion table.
-            let mut split = vec![Vec::new(); 16];
+            let mut split = vec![Vec::new(); vec.len()];
             for (k, v) in keyvals.iter() {
+                if k.is_empty() || k[0] as usize >= vec.len() {
+                    return Err(format!("Invalid key in full node: {:?}", k));
+                }
                 let idx = k[0] as usize;
                 split[idx].push((k[1..].to_vec(), v.to_vec()));
             }
@@ -363,25 +851,28 @@ pub fn make_multiproof(
             for (selector, subkeys) in split.iter().enumerate() {
                 // Does the child have any key? If not, it will be hashed
                 // and a `HASHER` instruction will be added to the list.
-                if split[selector].len() == 0 {
+                if split[selector].is_empty() {
                     // Empty slots are not to be hashed
-                    if vec[selector] != EmptySlot {
-                        instructions.push(Instruction::HASHER(0));
-                        instructions.push(Instruction::ADD(selector));
-                        hashes.push(vec[selector].hash(&mut vec![]));
+                    if vec[selector] == EmptySlot {
+                        continue;
                     }
+                    instructions.push(Instruction::HASHER(0));
+                    hashes.push(vec[selector].hash(&mut vec![]));
                 } else {
-                    let mut proof = make_multiproof(&vec[selector], subkeys.to_vec())?;
+                    let mut proof =
+                        make_multiproof_with_resolver(&vec[selector], subkeys.to_vec(), resolver)?;
                     instructions.append(&mut proof.instructions);
-                    if branch {
-                        instructions.push(Instruction::BRANCH(selector));
-                        branch = false;
-                    } else {
-                        instructions.push(Instruction::ADD(selector));
-                    }
                     hashes.append(&mut proof.hashes);
                     values.append(&mut proof.keyvals);
                 }
+                // The first child, be it hashed or not, creates the
+                // full node.
+                if branch {
+                    instructions.push(Instruction::BRANCH(selector));
+                    branch = false;
+                } else {
+                    instructions.push(Instruction::ADD(selector));
+                }
             }
         }
         Leaf(leafkey, _) => {
@@ -395,9 +886,9 @@ pub fn make_multiproof(
             }
 
             let key = &keyvals[0].0;
-            if *leafkey == NibbleKey::new(key.to_vec()) {
+            if leafkey.as_ref() == &key[..] {
                 instructions.push(Instruction::LEAF(key.len()));
-                let rlp = rlp::encode(&Leaf(NibbleKey::new(key.clone()), keyvals[0].1.clone()));
+                let rlp = rlp::encode(&Leaf(leafkey.clone(), keyvals[0].1.clone()));
                 values.push(rlp);
             } else {
                 return Err(
@@ -405,29 +896,32 @@ pub fn make_multiproof(
                 );
             }
         }
-        Extension(extkey, box child) => {
+        Extension(extkey, child) => {
             // Make sure that all the keys follow the extension and
             // if so, then recurse.
             let mut truncated = vec![];
             for (k, v) in keyvals.iter() {
-                if &k[..extkey.len()] != &extkey[..] {
+                if k.len() <= extkey.len() || k[..extkey.len()] != extkey[..] {
                     return Err(
                         format!("One of the keys isn't present in the tree: {:?}", k).to_string(),
                     );
                 }
-                truncated.push((k.to_vec(), v.to_vec()));
+                truncated.push((k[extkey.len()..].to_vec(), v.to_vec()));
             }
-            let mut proof = make_multiproof(child, truncated)?;
+            let mut proof = make_multiproof_with_resolver(child, truncated, resolver)?;
             hashes.append(&mut proof.hashes);
             instructions.append(&mut proof.instructions);
+            instructions.push(Instruction::EXTENSION(extkey.to_vec()));
             values.append(&mut proof.keyvals);
         }
-        Hash(_, _) => return Err("Should not have encountered a Hash in this context".to_string()),
+        Hash(h, _) => {
+            return make_multiproof_with_resolver(&resolve_hash(resolver, h)?, keyvals, resolver)
+        }
     }
 
     Ok(Multiproof {
-        instructions: instructions,
-        hashes: hashes,
+        instructions,
+        hashes,
         keyvals: values,
     })
 }
@@ -444,7 +938,7 @@ mod tests {
 
     #[test]
     fn validate_tree() {
-        let mut root = FullNode(vec![EmptySlot; 16]);
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
         insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
         insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
         insert_leaf(&mut root, vec![8u8; 32], vec![150u8; 32]).unwrap();
@@ -466,7 +960,7 @@ mod tests {
 
         assert_eq!(
             new_root,
-            FullNode(vec![
+            FullNode(Arc::new(vec![
                 EmptySlot,
                 Leaf(
                     NibbleKey::new(vec![
@@ -507,13 +1001,13 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
         );
     }
 
     #[test]
     fn make_multiproof_two_values() {
-        let mut root = FullNode(vec![EmptySlot; 16]);
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
         insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
         insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
         insert_leaf(&mut root, vec![8u8; 32], vec![150u8; 32]).unwrap();
@@ -533,24 +1027,24 @@ mod tests {
         match i[0] {
             // Key length is 31
             LEAF(n) => assert_eq!(n, 31),
-            _ => panic!(format!("Invalid instruction {:?}", i[0])),
+            _ => panic!("Invalid instruction {:?}", i[0]),
         }
         match i[1] {
             BRANCH(n) => assert_eq!(n, 1),
-            _ => panic!(format!("Invalid instruction {:?}", i[1])),
+            _ => panic!("Invalid instruction {:?}", i[1]),
         }
         match i[2] {
             // Key length is 31
             LEAF(n) => assert_eq!(n, 31),
-            _ => panic!(format!("Invalid instruction {:?}", i[2])),
+            _ => panic!("Invalid instruction {:?}", i[2]),
         }
         match i[3] {
             ADD(n) => assert_eq!(n, 2),
-            _ => panic!(format!("Invalid instruction {:?}", i[3])),
+            _ => panic!("Invalid instruction {:?}", i[3]),
         }
         match i[5] {
             ADD(n) => assert_eq!(n, 8),
-            _ => panic!(format!("Invalid instruction {:?}", i[5])),
+            _ => panic!("Invalid instruction {:?}", i[5]),
         }
         assert_eq!(h.len(), 1); // Only one hash
         assert_eq!(v.len(), 2);
@@ -566,7 +1060,7 @@ mod tests {
 
     #[test]
     fn make_multiproof_single_value() {
-        let mut root = FullNode(vec![EmptySlot; 16]);
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
         insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
         insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
 
@@ -578,19 +1072,19 @@ mod tests {
         match i[0] {
             // Key length is 31
             LEAF(n) => assert_eq!(n, 31),
-            _ => panic!(format!("Invalid instruction {:?}", i[0])),
+            _ => panic!("Invalid instruction {:?}", i[0]),
         }
         match i[1] {
             BRANCH(n) => assert_eq!(n, 1),
-            _ => panic!(format!("Invalid instruction {:?}", i[1])),
+            _ => panic!("Invalid instruction {:?}", i[1]),
         }
         match i[2] {
             HASHER(n) => assert_eq!(n, 0),
-            _ => panic!(format!("Invalid instruction {:?}", i[2])),
+            _ => panic!("Invalid instruction {:?}", i[2]),
         }
         match i[3] {
             ADD(n) => assert_eq!(n, 2),
-            _ => panic!(format!("Invalid instruction {:?}", i[3])),
+            _ => panic!("Invalid instruction {:?}", i[3]),
         }
         assert_eq!(h.len(), 1); // Only one hash
         assert_eq!(v.len(), 1); // Only one value
@@ -600,9 +1094,72 @@ mod tests {
         );
     }
 
+    #[test]
+    fn make_multiproof_hashed_child_first() {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
+        insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+
+        let proof = make_multiproof(&root, vec![(vec![2u8; 32], vec![0u8; 32])]).unwrap();
+        let i = &proof.instructions;
+        assert_eq!(i.len(), 4); // [HASHER, BRANCH, LEAF, ADD]
+        match i[1] {
+            BRANCH(n) => assert_eq!(n, 1),
+            _ => panic!("Invalid instruction {:?}", i[1]),
+        }
+        match i[3] {
+            ADD(n) => assert_eq!(n, 2),
+            _ => panic!("Invalid instruction {:?}", i[3]),
+        }
+        let rebuilt = rebuild(&mut vec![], &proof);
+        assert_eq!(rebuilt.hash(&mut vec![]), root.hash(&mut vec![]));
+    }
+
+    #[test]
+    fn verify_multiproof_rejects_malformed_proofs() {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+        insert_leaf(&mut root, vec![2u8; 32], vec![2u8; 32]).unwrap();
+        let hash = root.hash(&mut vec![]);
+        let proof = make_multiproof(&root, vec![(vec![1u8; 32], vec![1u8; 32])]).unwrap();
+        assert_eq!(
+            verify_multiproof(&hash, &proof).unwrap().hash(&mut vec![]),
+            hash
+        );
+
+        let mut missing = proof.clone();
+        missing.hashes.clear();
+        assert!(verify_multiproof(&hash, &missing).is_err());
+
+        let mut bad_index = proof.clone();
+        bad_index.instructions[1] = Instruction::BRANCH(16);
+        assert!(try_rebuild(&mut vec![], &bad_index).is_err());
+
+        let mut bad_keyval = proof.clone();
+        bad_keyval.keyvals[0] = vec![0xff];
+        assert!(try_rebuild(&mut vec![], &bad_keyval).is_err());
+    }
+
+    #[test]
+    fn make_multiproof_through_extension() {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        let mut key = vec![1u8; 32];
+        key[31] = 0;
+        insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+        insert_leaf(&mut root, key.clone(), vec![0u8; 32]).unwrap();
+
+        let proof = make_multiproof(&root, vec![(key, vec![0u8; 32])]).unwrap();
+        match proof.instructions[4] {
+            EXTENSION(ref ext) => assert_eq!(*ext, vec![1u8; 30]),
+            _ => panic!("Invalid instruction {:?}", proof.instructions[4]),
+        }
+        let rebuilt = rebuild(&mut vec![], &proof);
+        assert_eq!(rebuilt.hash(&mut vec![]), root.hash(&mut vec![]));
+    }
+
     #[test]
     fn make_multiproof_no_values() {
-        let mut root = FullNode(vec![EmptySlot; 16]);
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
         insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
         insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
 
@@ -617,7 +1174,7 @@ mod tests {
 
     #[test]
     fn make_multiproof_empty_tree() {
-        let root = FullNode(vec![EmptySlot; 16]);
+        let root = FullNode(Arc::new(vec![EmptySlot; 16]));
 
         let out = make_multiproof(&root, vec![(vec![1u8; 32], vec![1u8; 32])]);
         assert!(out.is_err());
@@ -627,7 +1184,7 @@ mod tests {
     fn insert_leaf_zero_length_key_after_fullnode() {
         let mut root = Extension(
             vec![0u8; 31],
-            Box::new(FullNode(vec![
+            Arc::new(FullNode(Arc::new(vec![
                 EmptySlot,
                 Leaf(NibbleKey::new(vec![]), vec![0u8; 32]),
                 EmptySlot,
@@ -644,14 +1201,14 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot,
-            ])),
+            ]))),
         );
         let out = insert_leaf(&mut root, vec![0u8; 32], vec![1u8; 32]).unwrap();
         assert_eq!(
             out,
             Extension(
                 vec![0u8; 31],
-                Box::new(FullNode(vec![
+                Arc::new(FullNode(Arc::new(vec![
                     Leaf(NibbleKey::new(vec![]), vec![1u8; 32]),
                     Leaf(NibbleKey::new(vec![]), vec![0u8; 32]),
                     EmptySlot,
@@ -668,7 +1225,7 @@ mod tests {
                     EmptySlot,
                     EmptySlot,
                     EmptySlot
-                ]))
+                ])))
             )
         );
     }
@@ -677,7 +1234,7 @@ mod tests {
     fn insert_leaf_into_extension_root_all_bytes_in_key_common() {
         let mut root = Extension(
             vec![0xd, 0xe, 0xa, 0xd],
-            Box::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32])),
+            Arc::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32])),
         );
         let mut key = vec![1u8; 32];
         key[0] = 0xd;
@@ -689,7 +1246,7 @@ mod tests {
             out,
             Extension(
                 vec![0xd, 0xe, 0xa, 0xd],
-                Box::new(FullNode(vec![
+                Arc::new(FullNode(Arc::new(vec![
                     Leaf(NibbleKey::new(vec![0u8; 27]), vec![1u8; 32]),
                     Leaf(NibbleKey::new(vec![1u8; 27]), vec![1u8; 32]),
                     EmptySlot,
@@ -706,7 +1263,7 @@ mod tests {
                     EmptySlot,
                     EmptySlot,
                     EmptySlot
-                ]))
+                ])))
             )
         );
     }
@@ -715,12 +1272,12 @@ mod tests {
     fn insert_leaf_into_extension_root_no_common_bytes_in_key() {
         let mut root = Extension(
             vec![0xd, 0xe, 0xa, 0xd],
-            Box::new(Leaf(NibbleKey::new(vec![0u8; 24]), vec![1u8; 32])),
+            Arc::new(Leaf(NibbleKey::new(vec![0u8; 24]), vec![1u8; 32])),
         );
         let out = insert_leaf(&mut root, vec![2u8; 32], vec![1u8; 32]).unwrap();
         assert_eq!(
             out,
-            FullNode(vec![
+            FullNode(Arc::new(vec![
                 EmptySlot,
                 EmptySlot,
                 Leaf(NibbleKey::new(vec![2u8; 31]), vec![1u8; 32]),
@@ -736,11 +1293,11 @@ mod tests {
                 EmptySlot,
                 Extension(
                     vec![14, 10, 13],
-                    Box::new(Leaf(NibbleKey::new(vec![0u8; 24]), vec![1u8; 32]))
+                    Arc::new(Leaf(NibbleKey::new(vec![0u8; 24]), vec![1u8; 32]))
                 ),
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
         );
     }
 
@@ -748,7 +1305,7 @@ mod tests {
     fn insert_leaf_into_extension_root_half_bytes_in_key_common() {
         let mut root = Extension(
             vec![0xd, 0xe, 0xa, 0xd],
-            Box::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32])),
+            Arc::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32])),
         );
         let mut key = vec![0u8; 32];
         key[0] = 0xd;
@@ -758,7 +1315,7 @@ mod tests {
             out,
             Extension(
                 vec![0xd, 0xe],
-                Box::new(FullNode(vec![
+                Arc::new(FullNode(Arc::new(vec![
                     Leaf(NibbleKey::new(vec![0u8; 29]), vec![1u8; 32]),
                     EmptySlot,
                     EmptySlot,
@@ -771,14 +1328,14 @@ mod tests {
                     EmptySlot,
                     Extension(
                         vec![0xd],
-                        Box::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32]))
+                        Arc::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32]))
                     ),
                     EmptySlot,
                     EmptySlot,
                     EmptySlot,
                     EmptySlot,
                     EmptySlot
-                ]))
+                ])))
             )
         );
     }
@@ -787,7 +1344,7 @@ mod tests {
     fn insert_leaf_into_extension_root_almost_all_bytes_in_key_common() {
         let mut root = Extension(
             vec![0xd, 0xe, 0xa, 0xd],
-            Box::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32])),
+            Arc::new(Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32])),
         );
         let mut key = vec![0u8; 32];
         key[0] = 0xd;
@@ -798,7 +1355,7 @@ mod tests {
             out,
             Extension(
                 vec![0xd, 0xe, 0xa],
-                Box::new(FullNode(vec![
+                Arc::new(FullNode(Arc::new(vec![
                     Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32]),
                     EmptySlot,
                     EmptySlot,
@@ -815,7 +1372,7 @@ mod tests {
                     Leaf(NibbleKey::new(vec![0u8; 28]), vec![1u8; 32]),
                     EmptySlot,
                     EmptySlot
-                ]))
+                ])))
             )
         );
     }
@@ -835,7 +1392,7 @@ mod tests {
             out,
             Extension(
                 vec![2u8; 16],
-                Box::new(FullNode(vec![
+                Arc::new(FullNode(Arc::new(vec![
                     Leaf(NibbleKey::new(vec![0u8; 15]), vec![1u8; 32]),
                     EmptySlot,
                     Leaf(NibbleKey::new(vec![2u8; 15]), vec![1u8; 32]),
@@ -852,7 +1409,7 @@ mod tests {
                     EmptySlot,
                     EmptySlot,
                     EmptySlot
-                ]))
+                ])))
             )
         );
     }
@@ -863,7 +1420,7 @@ mod tests {
         let out = insert_leaf(&mut root, vec![2u8; 32], vec![1u8; 32]).unwrap();
         assert_eq!(
             out,
-            FullNode(vec![
+            FullNode(Arc::new(vec![
                 EmptySlot,
                 Leaf(NibbleKey::new(vec![1u8; 31]), vec![1u8; 32]),
                 Leaf(NibbleKey::new(vec![2u8; 31]), vec![1u8; 32]),
@@ -880,18 +1437,18 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
         );
     }
 
     #[test]
     fn insert_leaf_into_empty_root() {
         let children = vec![EmptySlot; 16];
-        let mut root = FullNode(children);
+        let mut root = FullNode(Arc::new(children));
         let out = insert_leaf(&mut root, vec![0u8; 32], vec![1u8; 32]);
         assert_eq!(
             out.unwrap(),
-            FullNode(vec![
+            FullNode(Arc::new(vec![
                 Leaf(NibbleKey::new(vec![0u8; 31]), vec![1u8; 32]),
                 EmptySlot,
                 EmptySlot,
@@ -908,14 +1465,14 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
         );
     }
 
     #[test]
     fn insert_leaf_into_two_level_fullnodes() {
-        let mut root = FullNode(vec![
-            FullNode(vec![EmptySlot; 16]),
+        let mut root = FullNode(Arc::new(vec![
+            FullNode(Arc::new(vec![EmptySlot; 16])),
             EmptySlot,
             EmptySlot,
             EmptySlot,
@@ -931,12 +1488,12 @@ mod tests {
             EmptySlot,
             EmptySlot,
             EmptySlot,
-        ]);
+        ]));
         let out = insert_leaf(&mut root, vec![0u8; 32], vec![1u8; 32]);
         assert_eq!(
             out.unwrap(),
-            FullNode(vec![
-                FullNode(vec![
+            FullNode(Arc::new(vec![
+                FullNode(Arc::new(vec![
                     Leaf(NibbleKey::new(vec![0u8; 30]), vec![1u8; 32]),
                     EmptySlot,
                     EmptySlot,
@@ -953,7 +1510,7 @@ mod tests {
                     EmptySlot,
                     EmptySlot,
                     EmptySlot
-                ]),
+                ])),
                 EmptySlot,
                 EmptySlot,
                 EmptySlot,
@@ -969,16 +1526,134 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
         );
     }
 
+    #[test]
+    fn insert_leaf_keeps_snapshot() {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
+        insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+        let snapshot = root.clone();
+        let snapshot_hash = snapshot.hash(&mut vec![]);
+
+        let mut key = vec![1u8; 32];
+        key[3] = 0;
+        insert_leaf(&mut root, key.clone(), vec![2u8; 32]).unwrap();
+
+        assert_eq!(snapshot.hash(&mut vec![]), snapshot_hash);
+        assert_ne!(root.hash(&mut vec![]), snapshot_hash);
+        assert_eq!(get(&snapshot, &key).unwrap(), None);
+        assert_eq!(get(&root, &key).unwrap(), Some(vec![2u8; 32]));
+    }
+
+    #[test]
+    fn insert_leaf_shares_untouched_subtrees() {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        for k in 0..4u8 {
+            let mut key = vec![k; 32];
+            key[31] = 15;
+            insert_leaf(&mut root, key, vec![k; 32]).unwrap();
+            insert_leaf(&mut root, vec![k; 32], vec![k; 32]).unwrap();
+        }
+        let snapshot = root.clone();
+        insert_leaf(&mut root, vec![5u8; 32], vec![5u8; 32]).unwrap();
+
+        match (&snapshot, &root) {
+            (FullNode(old), FullNode(new)) => {
+                assert!(!Arc::ptr_eq(old, new));
+                for i in 0..4 {
+                    match (&old[i], &new[i]) {
+                        (Extension(_, a), Extension(_, b)) => assert!(Arc::ptr_eq(a, b)),
+                        (a, b) => panic!("Unexpected children {:?} {:?}", a, b),
+                    }
+                }
+            }
+            _ => panic!("Root should be a full node"),
+        }
+    }
+
+    #[test]
+    fn get_from_tree() {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
+        insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+        let mut key = vec![1u8; 32];
+        key[31] = 0;
+        let root = insert_leaf(&mut root, key.clone(), vec![2u8; 32]).unwrap();
+
+        assert_eq!(get(&root, &[2u8; 32]).unwrap(), Some(vec![0u8; 32]));
+        assert_eq!(get(&root, &[1u8; 32]).unwrap(), Some(vec![1u8; 32]));
+        assert_eq!(get(&root, &key).unwrap(), Some(vec![2u8; 32]));
+        assert_eq!(get(&root, &[3u8; 32]).unwrap(), None);
+    }
+
+    #[test]
+    fn get_resolves_hash_root() {
+        let leaf = Leaf(NibbleKey::new(vec![1u8; 32]), vec![1u8; 32]);
+        let root = Hash(leaf.hash(&mut vec![]), 0);
+        assert!(get(&root, &[1u8; 32]).is_err());
+
+        let resolver = |_: &[u8]| Ok(leaf.clone());
+        assert_eq!(
+            get_with_resolver(&root, &[1u8; 32], &resolver).unwrap(),
+            Some(vec![1u8; 32])
+        );
+    }
+
+    #[test]
+    fn instruction_mnemonics() {
+        let instructions = [
+            LEAF(31),
+            BRANCH(1),
+            HASHER(0),
+            ADD(8),
+            EXTENSION(vec![0xa, 0xb]),
+            EXTENSION(vec![]),
+        ];
+        let text: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "LEAF 31",
+                "BRANCH 1",
+                "HASHER 0",
+                "ADD 8",
+                "EXTENSION 0a0b",
+                "EXTENSION -"
+            ]
+        );
+        for (s, i) in text.iter().zip(instructions.iter()) {
+            assert_eq!(s.parse::<Instruction>().unwrap(), *i);
+        }
+        assert_eq!("  ADD\t2 ".parse::<Instruction>().unwrap(), ADD(2));
+
+        for invalid in [
+            "",
+            "LEAF",
+            "LEAF 1 2",
+            "LEAF -1",
+            "leaf 1",
+            "JUMP 1",
+            "EXTENSION 0a0",
+            "EXTENSION +a",
+            "EXTENSION 0g",
+            "EXTENSION",
+            "EXTENSION --",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<Instruction>().is_err(), "{}", invalid);
+        }
+    }
+
     #[test]
     fn tree_with_just_one_leaf() {
         let mut stack = Vec::new();
         let proof = Multiproof {
             hashes: vec![],
-            keyvals: vec![rlp::encode_list::<Vec<u8>, Vec<u8>>(&vec![
+            keyvals: vec![rlp::encode_list::<Vec<u8>, Vec<u8>>(&[
                 vec![1, 2, 3],
                 vec![4, 5, 6],
             ])],
@@ -993,7 +1668,7 @@ mod tests {
         let mut stack = Vec::new();
         let proof = Multiproof {
             hashes: vec![],
-            keyvals: vec![rlp::encode_list::<Vec<u8>, Vec<u8>>(&vec![
+            keyvals: vec![rlp::encode_list::<Vec<u8>, Vec<u8>>(&[
                 vec![1, 2, 3],
                 vec![4, 5, 6],
             ])],
@@ -1002,7 +1677,7 @@ mod tests {
         let out = rebuild(&mut stack, &proof);
         assert_eq!(
             out,
-            FullNode(vec![
+            FullNode(Arc::new(vec![
                 Leaf(NibbleKey::new(vec![]), vec![4, 5, 6]),
                 EmptySlot,
                 EmptySlot,
@@ -1019,7 +1694,7 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
         )
     }
 
@@ -1029,15 +1704,15 @@ mod tests {
         let proof = Multiproof {
             hashes: vec![],
             keyvals: vec![
-                rlp::encode_list::<Vec<u8>, Vec<u8>>(&vec![vec![1, 2, 3], vec![4, 5, 6]]),
-                rlp::encode_list::<Vec<u8>, Vec<u8>>(&vec![vec![7, 8, 9], vec![10, 11, 12]]),
+                rlp::encode_list::<Vec<u8>, Vec<u8>>(&[vec![1, 2, 3], vec![4, 5, 6]]),
+                rlp::encode_list::<Vec<u8>, Vec<u8>>(&[vec![7, 8, 9], vec![10, 11, 12]]),
             ],
             instructions: vec![LEAF(0), BRANCH(0), LEAF(1), ADD(2)],
         };
         let out = rebuild(&mut stack, &proof);
         assert_eq!(
             out,
-            FullNode(vec![
+            FullNode(Arc::new(vec![
                 Leaf(NibbleKey::new(vec![]), vec![4, 5, 6]),
                 EmptySlot,
                 Leaf(NibbleKey::new(vec![9]), vec![10, 11, 12]),
@@ -1054,7 +1729,7 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
         )
     }
 
@@ -1071,8 +1746,8 @@ mod tests {
                 EXTENSION(vec![13, 14, 15]),
             ],
             keyvals: vec![
-                rlp::encode_list::<Vec<u8>, Vec<u8>>(&vec![vec![1, 2, 3], vec![4, 5, 6]]),
-                rlp::encode_list::<Vec<u8>, Vec<u8>>(&vec![vec![7, 8, 9], vec![10, 11, 12]]),
+                rlp::encode_list::<Vec<u8>, Vec<u8>>(&[vec![1, 2, 3], vec![4, 5, 6]]),
+                rlp::encode_list::<Vec<u8>, Vec<u8>>(&[vec![7, 8, 9], vec![10, 11, 12]]),
             ],
         };
         let out = rebuild(&mut stack, &proof);
@@ -1080,7 +1755,7 @@ mod tests {
             out,
             Extension(
                 vec![13, 14, 15],
-                Box::new(FullNode(vec![
+                Arc::new(FullNode(Arc::new(vec![
                     Leaf(NibbleKey::new(vec![]), vec![4, 5, 6]),
                     EmptySlot,
                     Leaf(NibbleKey::new(vec![9]), vec![10, 11, 12]),
@@ -1097,7 +1772,7 @@ mod tests {
                     EmptySlot,
                     EmptySlot,
                     EmptySlot
-                ]))
+                ])))
             )
         )
     }
@@ -1139,14 +1814,14 @@ mod tests {
     fn empty_value_hash() {
         let mut hashers = Vec::new();
         let node = EmptySlot;
-        assert_eq!(node.hash(&mut hashers), vec![]);
+        assert_eq!(node.hash(&mut hashers), Vec::<u8>::new());
     }
 
     #[test]
     fn full_node_hash() {
         let mut hashers = Vec::new();
         assert_eq!(
-            FullNode(vec![
+            FullNode(Arc::new(vec![
                 Leaf(NibbleKey::new(vec![]), vec![4, 5, 6]),
                 EmptySlot,
                 Leaf(NibbleKey::new(vec![9]), vec![10, 11, 12]),
@@ -1163,7 +1838,7 @@ mod tests {
                 EmptySlot,
                 EmptySlot,
                 EmptySlot
-            ])
+            ]))
             .hash(&mut hashers),
             vec![
                 220, 134, 193, 128, 131, 4, 5, 6, 128, 134, 193, 9, 131, 10, 11, 12, 128, 128, 128,
@@ -1171,4 +1846,18 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn hashers_are_reused() {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        for i in 0..16u8 {
+            root = insert_leaf(&mut root, vec![i; 32], vec![i; 32]).unwrap();
+        }
+        let mut hashers = Vec::new();
+        let hash = root.hash(&mut hashers);
+        assert_eq!(hashers.len(), 1);
+        assert_eq!(root.hash(&mut hashers), hash);
+        assert_eq!(hashers.len(), 1);
+        assert_eq!(root.hash(&mut vec![]), hash);
+    }
 }
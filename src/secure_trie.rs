@@ -0,0 +1,196 @@
+use super::iter::Entry;
+use super::trie::Trie;
+use super::*;
+use std::collections::HashMap;
+
+// Hash a key the way that Ethereum's state and storage trees do before
+// inserting it, i.e. `keccak256(key)`.
+pub fn hash_key(key: &ByteKey) -> NibbleKey {
+    let mut hasher = Keccak256::new();
+    hasher.input(key.as_ref());
+    NibbleKey::from(ByteKey::from(hasher.result().to_vec()))
+}
+
+// A `(key, value)` pair of a secure tree, as returned by `SecureTrie::iter`.
+#[derive(Debug, PartialEq)]
+pub struct SecureEntry<'a> {
+    pub hashed: NibbleKey,        // Key under which the value is stored
+    pub key: Option<&'a ByteKey>, // Original key, if its preimage is known
+    pub value: &'a [u8],
+}
+
+// A tree whose keys are hashed before they are inserted, so that its
+// structure can not be influenced by the choice of keys. The preimages
+// of the hashed keys can optionally be kept, in order to return the
+// original keys when iterating over the tree.
+#[derive(Debug, Clone, Default)]
+pub struct SecureTrie {
+    trie: Trie,
+    preimages: Option<HashMap<NibbleKey, ByteKey>>,
+}
+
+impl SecureTrie {
+    pub fn new() -> Self {
+        SecureTrie {
+            trie: Trie::new(),
+            preimages: None,
+        }
+    }
+
+    // Create a tree that remembers the original keys.
+    pub fn with_preimages() -> Self {
+        SecureTrie {
+            trie: Trie::new(),
+            preimages: Some(HashMap::new()),
+        }
+    }
+
+    // The underlying tree, indexed by hashed keys.
+    pub fn trie(&self) -> &Trie {
+        &self.trie
+    }
+
+    pub fn root_hash(&self) -> Vec<u8> {
+        self.trie.root_hash()
+    }
+
+    pub fn get(&self, key: &ByteKey) -> Result<Option<Vec<u8>>, String> {
+        self.trie.get(hash_key(key))
+    }
+
+    // Associate `value` to `key`, and return the previous value if the
+    // key was already present.
+    pub fn insert<V: Into<Vec<u8>>>(
+        &mut self,
+        key: &ByteKey,
+        value: V,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let hashed = hash_key(key);
+        let previous = self.trie.insert(hashed.clone(), value)?;
+        if let Some(ref mut preimages) = self.preimages {
+            preimages.insert(hashed, key.clone());
+        }
+        Ok(previous)
+    }
+
+    // Remove `key` from the tree, and return its value if it was present.
+    pub fn remove(&mut self, key: &ByteKey) -> Result<Option<Vec<u8>>, String> {
+        let hashed = hash_key(key);
+        let previous = self.trie.remove(hashed.clone())?;
+        if let Some(ref mut preimages) = self.preimages {
+            preimages.remove(&hashed);
+        }
+        Ok(previous)
+    }
+
+    // Build a multiproof of the current values of `keys`. The proof is
+    // over the hashed keys.
+    pub fn prove<'a, I>(&self, keys: I) -> Result<Multiproof, String>
+    where
+        I: IntoIterator<Item = &'a ByteKey>,
+    {
+        self.trie.prove(keys.into_iter().map(hash_key))
+    }
+
+    // Return the original key of a hashed key, if it is known.
+    pub fn preimage(&self, hashed: &NibbleKey) -> Option<&ByteKey> {
+        self.preimages.as_ref().and_then(|p| p.get(hashed))
+    }
+
+    // Iterate over the `(key, value)` pairs, in hashed key order.
+    pub fn iter(&self) -> impl Iterator<Item = SecureEntry<'_>> {
+        // A secure tree is always built by insertion, so it can not
+        // contain any `Hash` node.
+        self.trie
+            .root()
+            .iter()
+            .filter_map(move |entry| match entry {
+                Entry::Leaf(hashed, value) => Some(SecureEntry {
+                    key: self.preimage(&hashed),
+                    hashed,
+                    value,
+                }),
+                Entry::Hash(_, _) => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(i: u8) -> ByteKey {
+        ByteKey::from(vec![i; 20])
+    }
+
+    #[test]
+    fn keys_are_hashed() {
+        let mut trie = SecureTrie::new();
+        trie.insert(&address(1), vec![1u8; 32]).unwrap();
+        assert_eq!(trie.get(&address(1)).unwrap(), Some(vec![1u8; 32]));
+        assert_eq!(trie.get(&address(2)).unwrap(), None);
+        assert_eq!(
+            trie.trie().get(hash_key(&address(1))).unwrap(),
+            Some(vec![1u8; 32])
+        );
+        assert_eq!(trie.trie().get(address(1)).unwrap(), None);
+    }
+
+    #[test]
+    fn hash_key_value() {
+        // keccak256 of the empty string
+        let expected = ByteKey::from(vec![
+            0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7,
+            0x03, 0xc0, 0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04,
+            0x5d, 0x85, 0xa4, 0x70,
+        ]);
+        assert_eq!(hash_key(&ByteKey::from(vec![])), NibbleKey::from(expected));
+    }
+
+    #[test]
+    fn iterate_with_preimages() {
+        let mut trie = SecureTrie::with_preimages();
+        for i in 0..10u8 {
+            trie.insert(&address(i), vec![i; 32]).unwrap();
+        }
+        trie.remove(&address(3)).unwrap();
+
+        let entries: Vec<SecureEntry> = trie.iter().collect();
+        assert_eq!(entries.len(), 9);
+        for entry in entries.iter() {
+            let key = entry.key.unwrap();
+            assert_eq!(entry.hashed, hash_key(key));
+            assert_eq!(entry.value, &[key.as_ref()[0]; 32][..]);
+        }
+        assert!(entries
+            .windows(2)
+            .all(|w| w[0].hashed.as_ref() < w[1].hashed.as_ref()));
+        assert_eq!(trie.preimage(&hash_key(&address(3))), None);
+    }
+
+    #[test]
+    fn iterate_without_preimages() {
+        let mut trie = SecureTrie::new();
+        trie.insert(&address(1), vec![1u8; 32]).unwrap();
+        let entries: Vec<SecureEntry> = trie.iter().collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, None);
+        assert_eq!(entries[0].hashed, hash_key(&address(1)));
+    }
+
+    #[test]
+    fn prove_hashed_keys() {
+        let mut trie = SecureTrie::new();
+        for i in 0..10u8 {
+            trie.insert(&address(i), vec![i; 32]).unwrap();
+        }
+        let proof = trie.prove(&[address(2), address(7)]).unwrap();
+        assert_eq!(proof.keyvals.len(), 2);
+        let rebuilt = Trie::from_root(rebuild(&mut vec![], &proof));
+        assert_eq!(rebuilt.root_hash(), trie.root_hash());
+        assert_eq!(
+            rebuilt.get(hash_key(&address(7))).unwrap(),
+            Some(vec![7u8; 32])
+        );
+    }
+}
@@ -0,0 +1,208 @@
+// Serialization of byte fields: human-readable formats, e.g. JSON, get
+// `0x`-prefixed hex strings, while binary formats get the raw bytes.
+// `bytes` and `list` are meant to be used with `#[serde(with = ...)]`.
+
+use super::utils::*;
+#[cfg(not(feature = "std"))]
+use alloc::{format, string::String, vec::Vec};
+use core::fmt;
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+// Encode bytes as a `0x`-prefixed, lowercase hex string
+pub fn encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(2 + 2 * bytes.len());
+    s.push_str("0x");
+    for b in bytes.iter() {
+        s.push(HEX_DIGITS[(b >> 4) as usize] as char);
+        s.push(HEX_DIGITS[(b & 0xf) as usize] as char);
+    }
+    s
+}
+
+// Decode a hex string, with or without its `0x` prefix
+pub fn decode(s: &str) -> Result<Vec<u8>, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s).as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("Hex string {} has an odd length", s));
+    }
+    let value = |c: u8| match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(format!("Invalid hex character {:?} in {}", c as char, s)),
+    };
+    digits
+        .chunks(2)
+        .map(|pair| Ok((value(pair[0])? << 4) | value(pair[1])?))
+        .collect()
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte array")
+    }
+
+    fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element()? {
+            v.push(b);
+        }
+        Ok(v)
+    }
+}
+
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            decode(&s).map_err(D::Error::custom)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+// Wrappers to (de)serialize each item of a list with `bytes`
+struct Hex<'a>(&'a [u8]);
+struct HexBuf(Vec<u8>);
+
+impl Serialize for Hex<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HexBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        bytes::deserialize(deserializer).map(HexBuf)
+    }
+}
+
+pub mod list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(|item| Hex(item)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<u8>>, D::Error> {
+        let list = Vec::<HexBuf>::deserialize(deserializer)?;
+        Ok(list.into_iter().map(|item| item.0).collect())
+    }
+}
+
+// Each digit of a `NibbleKey` is written as one byte, so that keys of any
+// width can be represented.
+impl Serialize for NibbleKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(self.as_ref(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for NibbleKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        bytes::deserialize(deserializer).map(NibbleKey::from_digits_unchecked)
+    }
+}
+
+impl Serialize for ByteKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        bytes::serialize(self.as_ref(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        bytes::deserialize(deserializer).map(ByteKey::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(encode(&[]), "0x");
+        assert_eq!(encode(&[0, 1, 0xab, 0xff]), "0x0001abff");
+        assert_eq!(decode("0x0001abff").unwrap(), vec![0, 1, 0xab, 0xff]);
+        assert_eq!(decode("0001ABFF").unwrap(), vec![0, 1, 0xab, 0xff]);
+        assert!(decode("0x123").is_err());
+        assert!(decode("0xzz").is_err());
+    }
+
+    #[test]
+    fn keys_as_hex_strings() {
+        let key = NibbleKey::new(vec![1u8, 2, 3]);
+        assert_eq!(serde_json::to_string(&key).unwrap(), "\"0x010203\"");
+        let decoded: NibbleKey = serde_json::from_str("\"0x010203\"").unwrap();
+        assert_eq!(decoded, key);
+
+        let key = ByteKey::from(vec![0xde, 0xad]);
+        assert_eq!(serde_json::to_string(&key).unwrap(), "\"0xdead\"");
+        let decoded: ByteKey = serde_json::from_str("\"0xdead\"").unwrap();
+        assert_eq!(decoded, key);
+
+        assert!(serde_json::from_str::<ByteKey>("\"0xdea\"").is_err());
+    }
+
+    #[test]
+    fn tree_round_trip() {
+        let mut root = Node::FullNode(Arc::new(vec![Node::EmptySlot; 16]));
+        root = insert_leaf(&mut root, vec![1u8; 4], vec![1u8; 3]).unwrap();
+        root = insert_leaf(&mut root, vec![1u8, 1, 2, 2], vec![2u8; 3]).unwrap();
+        root = insert_leaf(&mut root, vec![2u8; 4], vec![3u8; 3]).unwrap();
+
+        let json = serde_json::to_string(&root).unwrap();
+        assert!(json.contains("\"0x030303\""));
+        let decoded: Node = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, root);
+    }
+
+    #[test]
+    fn proof_round_trip() {
+        let proof = Multiproof {
+            hashes: vec![vec![0xab; 32]],
+            instructions: vec![
+                Instruction::LEAF(1),
+                Instruction::EXTENSION(vec![1, 2]),
+                Instruction::HASHER(0),
+                Instruction::ADD(3),
+            ],
+            keyvals: vec![vec![0xc2, 1, 2]],
+        };
+        let json = serde_json::to_string(&proof).unwrap();
+        assert!(json.contains("\"0xc20102\""));
+        assert!(json.contains("{\"EXTENSION\":\"0x0102\"}"));
+        let decoded: Multiproof = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, proof);
+    }
+}
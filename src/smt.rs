@@ -0,0 +1,428 @@
+use super::*;
+use std::collections::BTreeMap;
+
+// The depth of the tree, i.e. the number of bits in a key.
+pub const DEPTH: usize = 256;
+
+pub type SmtKey = [u8; 32];
+
+fn keccak(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(data);
+    hasher.result().to_vec()
+}
+
+// The hash of an internal node, from the hashes of its children.
+pub fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Keccak256::new();
+    hasher.input(left);
+    hasher.input(right);
+    hasher.result().to_vec()
+}
+
+// The hash of a leaf: absent keys hash to zero, and the other ones to
+// the hash of their value.
+pub fn leaf_hash(value: &[u8]) -> Vec<u8> {
+    if value.is_empty() {
+        vec![0u8; 32]
+    } else {
+        keccak(value)
+    }
+}
+
+// The bit of `key` that selects a child at `depth`, most significant
+// bit first.
+fn bit(key: &SmtKey, depth: usize) -> u8 {
+    (key[depth / 8] >> (7 - depth % 8)) & 1
+}
+
+// The hashes of empty subtrees, indexed by height: an empty leaf hashes
+// to zero, and an empty subtree of height `h + 1` hashes to the hash of
+// two empty subtrees of height `h`.
+pub fn default_hashes() -> Vec<Vec<u8>> {
+    let mut defaults = vec![vec![0u8; 32]];
+    for h in 0..DEPTH {
+        let hash = hash_pair(&defaults[h], &defaults[h]);
+        defaults.push(hash);
+    }
+    defaults
+}
+
+// Split a sorted list at the first key whose bit at `depth` is 1.
+fn split_index<T>(items: &[T], depth: usize, key: impl Fn(&T) -> &SmtKey) -> usize {
+    items
+        .iter()
+        .position(|item| bit(key(item), depth) == 1)
+        .unwrap_or(items.len())
+}
+
+// A proof of a set of keys in a sparse Merkle tree. The siblings of the
+// proven paths are visited depth-first, left to right. The bitmap has
+// one bit per sibling, most significant bit first: siblings that are
+// empty subtrees have a 0 bit, and the others have a 1 bit and their
+// hash in `hashes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmtProof {
+    pub keys: Vec<SmtKey>,    // Proven keys, in increasing order
+    pub values: Vec<Vec<u8>>, // Values of the proven keys, empty if absent
+    pub bitmap: Vec<u8>,      // Which siblings are not empty
+    pub hashes: Vec<Vec<u8>>, // Hashes of the non-empty siblings
+}
+
+// A fixed-depth binary tree with 256-bit keys, in which every possible
+// key has a leaf. Absent keys have an empty leaf, so that all the empty
+// subtrees of a given height have the same, precomputed, hash and only
+// the non-empty leaves need to be stored.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree {
+    leaves: BTreeMap<SmtKey, Vec<u8>>,
+    defaults: Vec<Vec<u8>>,
+}
+
+impl Default for SparseMerkleTree {
+    fn default() -> Self {
+        SparseMerkleTree::new()
+    }
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        SparseMerkleTree {
+            leaves: BTreeMap::new(),
+            defaults: default_hashes(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn get(&self, key: &SmtKey) -> Option<Vec<u8>> {
+        self.leaves.get(key).cloned()
+    }
+
+    // Associate `value` to `key`, and return the previous value if the
+    // key was already present. Empty values are reserved for absent keys.
+    pub fn insert(&mut self, key: SmtKey, value: Vec<u8>) -> Result<Option<Vec<u8>>, String> {
+        if value.is_empty() {
+            return Err(format!("Attempted to insert an empty value at {:?}", key));
+        }
+        Ok(self.leaves.insert(key, value))
+    }
+
+    // Remove `key` from the tree, and return its value if it was present.
+    pub fn remove(&mut self, key: &SmtKey) -> Option<Vec<u8>> {
+        self.leaves.remove(key)
+    }
+
+    pub fn root_hash(&self) -> Vec<u8> {
+        let leaves: Vec<(&SmtKey, &Vec<u8>)> = self.leaves.iter().collect();
+        self.subtree_hash(&leaves, 0)
+    }
+
+    // Hash of the subtree at `depth` that holds the sorted `leaves`.
+    // Empty subtrees aren't descended into.
+    fn subtree_hash(&self, leaves: &[(&SmtKey, &Vec<u8>)], depth: usize) -> Vec<u8> {
+        if leaves.is_empty() {
+            return self.defaults[DEPTH - depth].clone();
+        }
+        if depth == DEPTH {
+            return keccak(leaves[0].1);
+        }
+        let split = split_index(leaves, depth, |(k, _)| *k);
+        hash_pair(
+            &self.subtree_hash(&leaves[..split], depth + 1),
+            &self.subtree_hash(&leaves[split..], depth + 1),
+        )
+    }
+
+    fn prove_subtree(
+        &self,
+        leaves: &[(&SmtKey, &Vec<u8>)],
+        keys: &[SmtKey],
+        depth: usize,
+        bits: &mut Vec<bool>,
+        hashes: &mut Vec<Vec<u8>>,
+    ) {
+        if depth == DEPTH {
+            return;
+        }
+        let leafsplit = split_index(leaves, depth, |(k, _)| *k);
+        let keysplit = split_index(keys, depth, |k| k);
+        let sides = [
+            (&leaves[..leafsplit], &keys[..keysplit]),
+            (&leaves[leafsplit..], &keys[keysplit..]),
+        ];
+        for (subleaves, subkeys) in sides.iter() {
+            if !subkeys.is_empty() {
+                self.prove_subtree(subleaves, subkeys, depth + 1, bits, hashes);
+            } else if subleaves.is_empty() {
+                bits.push(false);
+            } else {
+                bits.push(true);
+                hashes.push(self.subtree_hash(subleaves, depth + 1));
+            }
+        }
+    }
+
+    // Build a proof of the values of `keys`. Keys that aren't present in
+    // the tree are proven to be absent.
+    pub fn prove(&self, keys: &[SmtKey]) -> Result<SmtProof, String> {
+        if keys.is_empty() {
+            return Err("No key to prove".to_string());
+        }
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys.dedup();
+
+        let leaves: Vec<(&SmtKey, &Vec<u8>)> = self.leaves.iter().collect();
+        let mut bits = Vec::new();
+        let mut hashes = Vec::new();
+        self.prove_subtree(&leaves, &keys, 0, &mut bits, &mut hashes);
+
+        let mut bitmap = vec![0u8; bits.len().div_ceil(8)];
+        for (i, b) in bits.iter().enumerate() {
+            if *b {
+                bitmap[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        let values = keys
+            .iter()
+            .map(|k| self.leaves.get(k).cloned().unwrap_or_default())
+            .collect();
+
+        Ok(SmtProof {
+            keys,
+            values,
+            bitmap,
+            hashes,
+        })
+    }
+}
+
+impl VerifyMultiproof for SparseMerkleTree {
+    type Proof = SmtProof;
+    type Proven = Vec<(SmtKey, Vec<u8>)>;
+
+    // Check `proof` against the root hash of a tree, and return the proven
+    // `(key, value)` pairs. Absent keys have an empty value.
+    fn verify_multiproof(
+        root_hash: &[u8],
+        proof: &SmtProof,
+    ) -> Result<Vec<(SmtKey, Vec<u8>)>, String> {
+        if proof.keys.is_empty() || proof.keys.len() != proof.values.len() {
+            return Err("Invalid number of keys in proof".to_string());
+        }
+        if proof.keys.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("Proof keys are not sorted".to_string());
+        }
+
+        let mut verifier = SmtVerifier {
+            defaults: default_hashes(),
+            proof,
+            bit: 0,
+            hash: 0,
+        };
+        let hash = verifier.subtree_hash(0, proof.keys.len(), 0)?;
+
+        if verifier.hash != proof.hashes.len() {
+            return Err("Proof has too many hashes".to_string());
+        }
+        if verifier.bit.div_ceil(8) != proof.bitmap.len()
+            || (verifier.bit..proof.bitmap.len() * 8).any(|i| verifier.read_bit(i))
+        {
+            return Err("Proof has an invalid bitmap".to_string());
+        }
+        if hash != root_hash {
+            return Err(format!(
+                "Invalid proof: expected root hash {:?}, got {:?}",
+                root_hash, hash
+            ));
+        }
+
+        Ok(proof
+            .keys
+            .iter()
+            .cloned()
+            .zip(proof.values.iter().cloned())
+            .collect())
+    }
+}
+
+// Recomputes the root hash of an `SmtProof`, reading its bitmap and its
+// hashes in the order in which they were produced.
+struct SmtVerifier<'a> {
+    defaults: Vec<Vec<u8>>,
+    proof: &'a SmtProof,
+    bit: usize,  // Index of the next bit to read in the bitmap
+    hash: usize, // Index of the next hash to read
+}
+
+impl<'a> SmtVerifier<'a> {
+    fn read_bit(&self, i: usize) -> bool {
+        self.proof.bitmap[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+
+    fn sibling(&mut self, depth: usize) -> Result<Vec<u8>, String> {
+        if self.bit >= self.proof.bitmap.len() * 8 {
+            return Err("Proof bitmap is too short".to_string());
+        }
+        let present = self.read_bit(self.bit);
+        self.bit += 1;
+        if !present {
+            return Ok(self.defaults[DEPTH - depth].clone());
+        }
+        let hash = self
+            .proof
+            .hashes
+            .get(self.hash)
+            .ok_or_else(|| "Proof requires one more hash".to_string())?;
+        self.hash += 1;
+        Ok(hash.to_vec())
+    }
+
+    // Hash of the subtree at `depth` that holds the proven keys in
+    // `start..end`.
+    fn subtree_hash(&mut self, start: usize, end: usize, depth: usize) -> Result<Vec<u8>, String> {
+        if depth == DEPTH {
+            return Ok(leaf_hash(&self.proof.values[start]));
+        }
+        let split = start + split_index(&self.proof.keys[start..end], depth, |k| k);
+        let left = if split > start {
+            self.subtree_hash(start, split, depth + 1)?
+        } else {
+            self.sibling(depth + 1)?
+        };
+        let right = if end > split {
+            self.subtree_hash(split, end, depth + 1)?
+        } else {
+            self.sibling(depth + 1)?
+        };
+        Ok(hash_pair(&left, &right))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::binary::BinaryNode;
+    use super::*;
+
+    fn key(i: u8) -> SmtKey {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&keccak(&[i]));
+        key
+    }
+
+    fn sample_tree(count: u8) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for i in 0..count {
+            tree.insert(key(i), vec![i; 32]).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn empty_tree_root() {
+        let defaults = default_hashes();
+        assert_eq!(SparseMerkleTree::new().root_hash(), defaults[DEPTH]);
+    }
+
+    #[test]
+    fn single_leaf_root() {
+        let mut tree = SparseMerkleTree::new();
+        tree.insert([0u8; 32], vec![1u8]).unwrap();
+
+        // The leaf is the leftmost one, so all its siblings are on the right
+        let defaults = default_hashes();
+        let mut hash = keccak(&[1u8]);
+        for default in defaults.iter().take(DEPTH) {
+            hash = hash_pair(&hash, default);
+        }
+        assert_eq!(tree.root_hash(), hash);
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut tree = sample_tree(20);
+        let root = tree.root_hash();
+        assert_eq!(tree.len(), 20);
+        assert_eq!(tree.get(&key(3)), Some(vec![3u8; 32]));
+        assert_eq!(tree.get(&key(30)), None);
+        assert!(tree.insert(key(30), vec![]).is_err());
+
+        tree.insert(key(30), vec![30u8]).unwrap();
+        assert_ne!(tree.root_hash(), root);
+        assert_eq!(tree.remove(&key(30)), Some(vec![30u8]));
+        assert_eq!(tree.root_hash(), root);
+    }
+
+    #[test]
+    fn prove_and_verify() {
+        let tree = sample_tree(20);
+        let root = tree.root_hash();
+        let keys = vec![key(7), key(1), key(12), key(100)];
+        let proof = tree.prove(&keys).unwrap();
+        assert_eq!(proof.keys.len(), 4);
+
+        let proven = SparseMerkleTree::verify_multiproof(&root, &proof).unwrap();
+        for (k, v) in proven.iter() {
+            assert_eq!(tree.get(k).unwrap_or_default(), *v);
+        }
+        assert!(proven.contains(&(key(100), vec![])));
+
+        assert!(SparseMerkleTree::verify_multiproof(&[0u8; 32], &proof).is_err());
+        let mut tampered = proof.clone();
+        tampered.values[0] = vec![0u8; 32];
+        assert!(SparseMerkleTree::verify_multiproof(&root, &tampered).is_err());
+        let mut truncated = proof.clone();
+        truncated.bitmap.pop();
+        assert!(SparseMerkleTree::verify_multiproof(&root, &truncated).is_err());
+    }
+
+    #[test]
+    fn default_siblings_are_omitted() {
+        let tree = sample_tree(1);
+        let proof = tree.prove(&[key(0)]).unwrap();
+        assert!(proof.hashes.is_empty());
+        assert_eq!(proof.bitmap, vec![0u8; DEPTH / 8]);
+
+        // With two leaves, the only non-default sibling is the other leaf
+        let tree = sample_tree(2);
+        let proof = tree.prove(&[key(0)]).unwrap();
+        assert_eq!(proof.hashes.len(), 1);
+        assert!(SparseMerkleTree::verify_multiproof(&tree.root_hash(), &proof).is_ok());
+    }
+
+    // Only uses the API that is shared by all the kinds of trees
+    fn verifies<T: VerifyMultiproof>(root_hash: &[u8], proof: &T::Proof) -> bool {
+        T::verify_multiproof(root_hash, proof).is_ok()
+    }
+
+    #[test]
+    fn shared_verify_api() {
+        let tree = sample_tree(10);
+        let proof = tree.prove(&[key(2), key(5)]).unwrap();
+        assert!(verifies::<SparseMerkleTree>(&tree.root_hash(), &proof));
+        assert!(!verifies::<SparseMerkleTree>(&[0u8; 32], &proof));
+
+        let mut root = Node::FullNode(Arc::new(vec![Node::EmptySlot; 16]));
+        let mut binary = BinaryNode::EmptySlot;
+        for i in 0..10u8 {
+            root = insert_leaf(&mut root, vec![i; 8], vec![i; 32]).unwrap();
+            binary = binary.insert(&[i & 1; 8], vec![i; 32]).unwrap();
+        }
+        let proof = make_multiproof(&root, vec![(vec![2u8; 8], vec![2u8; 32])]).unwrap();
+        assert!(verifies::<Node>(&root.hash(&mut vec![]), &proof));
+        assert!(!verifies::<Node>(&[0u8; 32], &proof));
+
+        let proof = binary
+            .make_multiproof(vec![(vec![1u8; 8], vec![9u8; 32])])
+            .unwrap();
+        assert!(verifies::<BinaryNode>(&binary.hash(), &proof));
+        assert!(!verifies::<BinaryNode>(&[0u8; 32], &proof));
+    }
+}
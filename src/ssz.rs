@@ -0,0 +1,578 @@
+use super::binary::*;
+use super::*;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+
+// The position of a node in a binary tree, as used by SSZ: the root is
+// at index 1, and the children of the node at index `i` are at `2i` and
+// `2i + 1`. The index is stored as its list of bits, most significant
+// first, so that it isn't limited to trees of depth 64 or 128.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GeneralizedIndex(Vec<u8>);
+
+impl GeneralizedIndex {
+    pub fn root() -> Self {
+        GeneralizedIndex(vec![1])
+    }
+
+    // The index of the node reached by following `path` from the root,
+    // 0 meaning left and 1 meaning right.
+    pub fn from_path(path: &[u8]) -> Result<Self, String> {
+        if path.iter().any(|b| *b > 1) {
+            return Err(format!("Invalid bit in path {:?}", path));
+        }
+        let mut bits = vec![1];
+        bits.extend_from_slice(path);
+        Ok(GeneralizedIndex(bits))
+    }
+
+    // Parse a big-endian integer.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let bits = bytes_to_bits(bytes);
+        match bits.iter().position(|b| *b == 1) {
+            Some(first) => Ok(GeneralizedIndex(bits[first..].to_vec())),
+            None => Err("Generalized index 0 is invalid".to_string()),
+        }
+    }
+
+    // The index as a big-endian integer, without leading zeroes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let padding = (8 - self.0.len() % 8) % 8;
+        let mut bits = vec![0u8; padding];
+        bits.extend_from_slice(&self.0);
+        bits.chunks(8)
+            .map(|byte| byte.iter().fold(0u8, |acc, b| (acc << 1) | b))
+            .collect()
+    }
+
+    // The path from the root to the node.
+    pub fn path(&self) -> &[u8] {
+        &self.0[1..]
+    }
+
+    pub fn depth(&self) -> usize {
+        self.0.len() - 1
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.len() == 1
+    }
+
+    pub fn child(&self, bit: u8) -> Self {
+        let mut bits = self.0.clone();
+        bits.push(bit);
+        GeneralizedIndex(bits)
+    }
+
+    // It panics if called on the root.
+    pub fn parent(&self) -> Self {
+        if self.is_root() {
+            panic!("The root has no parent");
+        }
+        GeneralizedIndex(self.0[..self.0.len() - 1].to_vec())
+    }
+
+    // It panics if called on the root.
+    pub fn sibling(&self) -> Self {
+        if self.is_root() {
+            panic!("The root has no sibling");
+        }
+        let mut bits = self.0.clone();
+        let last = bits.len() - 1;
+        bits[last] ^= 1;
+        GeneralizedIndex(bits)
+    }
+}
+
+// Indices are ordered like the integers they represent.
+impl Ord for GeneralizedIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .len()
+            .cmp(&other.0.len())
+            .then_with(|| self.0.cmp(&other.0))
+    }
+}
+
+impl PartialOrd for GeneralizedIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Compute the indices of the nodes whose hash is needed to prove the
+// nodes at `indices`, i.e. the siblings of the nodes along their paths
+// that can't be calculated from the proven nodes. They are returned in
+// decreasing order.
+pub fn helper_indices(indices: &[GeneralizedIndex]) -> Vec<GeneralizedIndex> {
+    let mut helpers = BTreeSet::new();
+    let mut paths = BTreeSet::new();
+    for index in indices.iter() {
+        let mut current = index.clone();
+        while !current.is_root() {
+            helpers.insert(current.sibling());
+            paths.insert(current.clone());
+            current = current.parent();
+        }
+    }
+    let mut helpers: Vec<GeneralizedIndex> = helpers.difference(&paths).cloned().collect();
+    helpers.reverse();
+    helpers
+}
+
+// Hashes the two children of a node into the hash of that node. SSZ
+// uses sha256 over their concatenation, and binary trees use
+// `BinaryNode::hash_pair`.
+pub trait PairHasher {
+    fn hash_pair(&self, left: &[u8], right: &[u8]) -> Vec<u8>;
+}
+
+impl<F> PairHasher for F
+where
+    F: Fn(&[u8], &[u8]) -> Vec<u8>,
+{
+    fn hash_pair(&self, left: &[u8], right: &[u8]) -> Vec<u8> {
+        self(left, right)
+    }
+}
+
+// A multiproof in the SSZ format: the hashes of the proven nodes along
+// with their generalized index, and the hashes of the helper nodes given
+// by `helper_indices`, in decreasing index order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SszMultiproof {
+    pub indices: Vec<GeneralizedIndex>,
+    pub leaves: Vec<Vec<u8>>,
+    pub helpers: Vec<Vec<u8>>,
+}
+
+// Compute the root hash of a tree from an SSZ multiproof, hashing the
+// children of each node with `hasher`.
+pub fn calculate_multi_merkle_root<H: PairHasher>(
+    proof: &SszMultiproof,
+    hasher: &H,
+) -> Result<Vec<u8>, String> {
+    if proof.leaves.len() != proof.indices.len() {
+        return Err("Proof has a different number of leaves and indices".to_string());
+    }
+    // The value of a node is only checked against its children if it is
+    // calculated, so a proven node can't be above another one.
+    let proven: BTreeSet<&GeneralizedIndex> = proof.indices.iter().collect();
+    if proven.len() != proof.indices.len() {
+        return Err("Proof has duplicate indices".to_string());
+    }
+    for index in proof.indices.iter() {
+        let mut current = index.clone();
+        while !current.is_root() {
+            current = current.parent();
+            if proven.contains(&current) {
+                return Err(format!("Index {:?} is an ancestor of {:?}", current, index));
+            }
+        }
+    }
+    let helpers = helper_indices(&proof.indices);
+    if proof.helpers.len() != helpers.len() {
+        return Err(format!(
+            "Proof requires {} helpers, got {}",
+            helpers.len(),
+            proof.helpers.len()
+        ));
+    }
+
+    let mut objects = BTreeMap::new();
+    for (index, hash) in proof.indices.iter().zip(proof.leaves.iter()) {
+        objects.insert(index.clone(), hash.to_vec());
+    }
+    for (index, hash) in helpers.into_iter().zip(proof.helpers.iter()) {
+        objects.insert(index, hash.to_vec());
+    }
+
+    // Visit the nodes in decreasing index order, so that the children of
+    // a node are always visited before it.
+    let mut keys: Vec<GeneralizedIndex> = objects.keys().rev().cloned().collect();
+    let mut pos = 0;
+    while pos < keys.len() {
+        let index = keys[pos].clone();
+        if !index.is_root() {
+            let parent = index.parent();
+            let sibling = index.sibling();
+            if objects.contains_key(&sibling) && !objects.contains_key(&parent) {
+                let (left, right) = if index.path().last() == Some(&0) {
+                    (&objects[&index], &objects[&sibling])
+                } else {
+                    (&objects[&sibling], &objects[&index])
+                };
+                let hash = hasher.hash_pair(left, right);
+                objects.insert(parent.clone(), hash);
+                keys.push(parent);
+            }
+        }
+        pos += 1;
+    }
+
+    objects
+        .remove(&GeneralizedIndex::root())
+        .ok_or_else(|| "Proof doesn't lead to the root".to_string())
+}
+
+// Check an SSZ multiproof against the root hash of a tree.
+pub fn verify_ssz_multiproof<H: PairHasher>(
+    root_hash: &[u8],
+    proof: &SszMultiproof,
+    hasher: &H,
+) -> Result<(), String> {
+    let hash = calculate_multi_merkle_root(proof, hasher)?;
+    if hash != root_hash {
+        return Err(format!(
+            "Invalid proof: expected root hash {:?}, got {:?}",
+            root_hash, hash
+        ));
+    }
+    Ok(())
+}
+
+type KeyVals = Vec<(Vec<u8>, Vec<u8>)>;
+
+// A proven leaf of a binary tree: its generalized index, its hash, and
+// its full key and value.
+type ProvenLeaf = (GeneralizedIndex, Vec<u8>, (Vec<u8>, Vec<u8>));
+
+// Walk a tree rebuilt from a binary proof, and collect its leaves and
+// the hashes of the subtrees that aren't proven. The skipped side of
+// each bit of an extension is an empty subtree.
+fn collect_binary(
+    node: &BinaryNode,
+    index: GeneralizedIndex,
+    leaves: &mut Vec<ProvenLeaf>,
+    known: &mut BTreeMap<GeneralizedIndex, Vec<u8>>,
+) {
+    use BinaryNode::*;
+
+    match node {
+        EmptySlot => {
+            known.insert(index, EMPTY_HASH.to_vec());
+        }
+        Hash(h) => {
+            known.insert(index, h.to_vec());
+        }
+        Leaf(leafkey, value) => {
+            let mut key = index.path().to_vec();
+            key.extend_from_slice(leafkey);
+            leaves.push((index, node.hash(), (key, value.to_vec())));
+        }
+        Extension(ext, child) => {
+            let mut index = index;
+            for bit in ext.iter() {
+                known.insert(index.child(1 - bit), EMPTY_HASH.to_vec());
+                index = index.child(*bit);
+            }
+            collect_binary(child, index, leaves, known);
+        }
+        Branch(children) => {
+            for (bit, child) in children.iter().enumerate() {
+                collect_binary(child, index.child(bit as u8), leaves, known);
+            }
+        }
+    }
+}
+
+// Convert a proof built by `BinaryNode::make_multiproof` into the SSZ
+// format. The leaves of the SSZ proof are the hashes of the proven leaf
+// nodes, at the index of their position in the tree, so the proven
+// `(key, value)` pairs are returned alongside it, in the same order.
+// The result is checked with `BinaryNode::hash_pair` as the hasher.
+pub fn binary_to_ssz(proof: &Multiproof) -> Result<(SszMultiproof, KeyVals), String> {
+    let root = BinaryNode::rebuild(proof)?;
+    let mut leaves = Vec::new();
+    let mut known = BTreeMap::new();
+    collect_binary(&root, GeneralizedIndex::root(), &mut leaves, &mut known);
+    if leaves.is_empty() {
+        return Err("Proof doesn't prove any key".to_string());
+    }
+
+    let mut indices = Vec::new();
+    let mut hashes = Vec::new();
+    let mut keyvals = Vec::new();
+    for (index, hash, keyval) in leaves.into_iter() {
+        indices.push(index);
+        hashes.push(hash);
+        keyvals.push(keyval);
+    }
+    let helpers = helper_indices(&indices)
+        .iter()
+        .map(|index| {
+            known
+                .remove(index)
+                .ok_or_else(|| format!("Proof has no hash for helper {:?}", index))
+        })
+        .collect::<Result<Vec<Vec<u8>>, String>>()?;
+    if !known.is_empty() {
+        return Err("Proof has hashes that aren't helpers".to_string());
+    }
+
+    Ok((
+        SszMultiproof {
+            indices,
+            leaves: hashes,
+            helpers,
+        },
+        keyvals,
+    ))
+}
+
+// Rebuild the part of a binary tree that is covered by the proven
+// `leaves`, which are sorted by key, below `index`. Helpers that are
+// empty subtrees become extensions of their sibling.
+fn build_binary(
+    index: GeneralizedIndex,
+    leaves: &[(&GeneralizedIndex, &Vec<u8>, &Vec<u8>)],
+    known: &BTreeMap<GeneralizedIndex, &Vec<u8>>,
+) -> Result<BinaryNode, String> {
+    use BinaryNode::*;
+
+    if leaves.is_empty() {
+        let hash = known
+            .get(&index)
+            .ok_or_else(|| format!("Proof has no hash for helper {:?}", index))?;
+        return Ok(if hash[..] == EMPTY_HASH[..] {
+            EmptySlot
+        } else {
+            Hash(hash.to_vec())
+        });
+    }
+    // The indices of the leaves are on the path of their key, so a leaf
+    // that isn't deeper than `index` is either at `index` or above it.
+    let depth = index.depth();
+    if leaves.iter().any(|(leaf, _, _)| leaf.depth() <= depth) {
+        if leaves.len() != 1 || *leaves[0].0 != index {
+            return Err(format!("Proof has overlapping indices at {:?}", index));
+        }
+        let (_, key, value) = leaves[0];
+        return Ok(Leaf(key[depth..].to_vec(), value.to_vec()));
+    }
+
+    let split = leaves
+        .iter()
+        .position(|(_, key, _)| key[depth] == 1)
+        .unwrap_or(leaves.len());
+    let left = build_binary(index.child(0), &leaves[..split], known)?;
+    let right = build_binary(index.child(1), &leaves[split..], known)?;
+    let (bit, child) = match (left, right) {
+        (EmptySlot, child) => (1, child),
+        (child, EmptySlot) => (0, child),
+        (left, right) => return Ok(Branch(Arc::new([left, right]))),
+    };
+    Ok(match child {
+        Extension(ext, grandchild) => {
+            let mut bits = vec![bit];
+            bits.extend_from_slice(&ext);
+            Extension(bits, grandchild)
+        }
+        child => Extension(vec![bit], Arc::new(child)),
+    })
+}
+
+// Convert a proof in the SSZ format, along with the proven `(key, value)`
+// pairs in the same order as its leaves, back into a binary proof. The
+// keys have to be sorted and unique, and each leaf has to match its key
+// and value.
+pub fn ssz_to_binary(
+    proof: &SszMultiproof,
+    keyvals: &[(Vec<u8>, Vec<u8>)],
+) -> Result<Multiproof, String> {
+    if proof.indices.len() != keyvals.len() || proof.leaves.len() != keyvals.len() {
+        return Err("Invalid number of values in proof".to_string());
+    }
+    if keyvals.is_empty() {
+        return Err("Proof doesn't prove any key".to_string());
+    }
+    if keyvals.windows(2).any(|w| w[0].0 >= w[1].0) {
+        return Err("Proof keys are not sorted and unique".to_string());
+    }
+
+    let mut leaves = Vec::new();
+    for ((index, leaf), (key, value)) in proof.indices.iter().zip(proof.leaves.iter()).zip(keyvals)
+    {
+        if !key.starts_with(index.path()) {
+            return Err(format!("Index {:?} isn't on the path of {:?}", index, key));
+        }
+        let node = BinaryNode::Leaf(key[index.depth()..].to_vec(), value.to_vec());
+        if node.hash() != *leaf {
+            return Err(format!("Leaf at {:?} doesn't match its value", index));
+        }
+        leaves.push((index, key, value));
+    }
+
+    let helpers = helper_indices(&proof.indices);
+    if helpers.len() != proof.helpers.len() {
+        return Err(format!(
+            "Proof requires {} helpers, got {}",
+            helpers.len(),
+            proof.helpers.len()
+        ));
+    }
+    let known: BTreeMap<GeneralizedIndex, &Vec<u8>> =
+        helpers.into_iter().zip(proof.helpers.iter()).collect();
+
+    let root = build_binary(GeneralizedIndex::root(), &leaves, &known)?;
+    root.make_multiproof(keyvals.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BinaryNode::*;
+    use super::*;
+
+    fn index(i: u128) -> GeneralizedIndex {
+        GeneralizedIndex::from_bytes(&i.to_be_bytes()).unwrap()
+    }
+
+    fn keccak_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+        let mut hasher = Keccak256::new();
+        hasher.input(left);
+        hasher.input(right);
+        hasher.result().to_vec()
+    }
+
+    fn key(i: u8) -> Vec<u8> {
+        let mut hasher = Keccak256::new();
+        hasher.input([i]);
+        bytes_to_bits(&hasher.result()[..])
+    }
+
+    fn sample_tree(count: u8) -> BinaryNode {
+        let mut root = EmptySlot;
+        for i in 0..count {
+            root = root.insert(&key(i), vec![i; 32]).unwrap();
+        }
+        root
+    }
+
+    fn sample_proof(root: &BinaryNode, proven: &[u8]) -> Multiproof {
+        let mut keyvals: Vec<(Vec<u8>, Vec<u8>)> =
+            proven.iter().map(|i| (key(*i), vec![*i; 32])).collect();
+        keyvals.sort();
+        root.make_multiproof(keyvals).unwrap()
+    }
+
+    #[test]
+    fn index_arithmetic() {
+        assert_eq!(index(1), GeneralizedIndex::root());
+        assert_eq!(index(13).parent(), index(6));
+        assert_eq!(index(13).sibling(), index(12));
+        assert_eq!(index(6).child(1), index(13));
+        assert_eq!(index(13).depth(), 3);
+        assert_eq!(index(13).path(), &[1, 0, 1][..]);
+        assert_eq!(index(300).to_bytes(), vec![1, 44]);
+        assert!(index(7) < index(8));
+        assert!(index(9) > index(8));
+        assert!(GeneralizedIndex::from_bytes(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn helper_indices_of_spec_example() {
+        // Proving 9 and 14 in a tree of depth 3 requires 8, 15, 6 and 5
+        let helpers = helper_indices(&[index(9), index(14)]);
+        assert_eq!(helpers, vec![index(15), index(8), index(6), index(5)]);
+    }
+
+    #[test]
+    fn calculate_root_of_small_tree() {
+        let leaves: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 32]).collect();
+        let left = keccak_pair(&leaves[0], &leaves[1]);
+        let right = keccak_pair(&leaves[2], &leaves[3]);
+        let root = keccak_pair(&left, &right);
+
+        let proof = SszMultiproof {
+            indices: vec![index(5), index(6)],
+            leaves: vec![leaves[1].clone(), leaves[2].clone()],
+            helpers: vec![leaves[3].clone(), leaves[0].clone()],
+        };
+        assert!(verify_ssz_multiproof(&root, &proof, &keccak_pair).is_ok());
+        assert!(verify_ssz_multiproof(&left, &proof, &keccak_pair).is_err());
+        assert!(verify_ssz_multiproof(&root, &proof, &BinaryNode::hash_pair).is_err());
+
+        let mut missing = proof.clone();
+        missing.helpers.pop();
+        assert!(calculate_multi_merkle_root(&missing, &keccak_pair).is_err());
+
+        let mut duplicate = proof.clone();
+        duplicate.indices[1] = index(5);
+        assert!(calculate_multi_merkle_root(&duplicate, &keccak_pair).is_err());
+
+        // Supplying an ancestor of the proven leaves would let made-up
+        // leaves verify, since they would never be hashed.
+        let forged = SszMultiproof {
+            indices: vec![index(1), index(5), index(6)],
+            leaves: vec![root.clone(), vec![9u8; 32], vec![9u8; 32]],
+            helpers: proof.helpers.clone(),
+        };
+        assert!(verify_ssz_multiproof(&root, &forged, &keccak_pair).is_err());
+        let forged = SszMultiproof {
+            indices: vec![index(5), index(6), index(3)],
+            leaves: vec![vec![9u8; 32], leaves[2].clone(), right],
+            helpers: vec![leaves[0].clone()],
+        };
+        assert!(verify_ssz_multiproof(&root, &forged, &keccak_pair).is_err());
+    }
+
+    #[test]
+    fn binary_proof_roundtrip() {
+        let root = sample_tree(50);
+        let proof = sample_proof(&root, &[4, 17, 33]);
+
+        let (ssz, keyvals) = binary_to_ssz(&proof).unwrap();
+        assert_eq!(keyvals.len(), 3);
+        assert_eq!(ssz.helpers.len(), helper_indices(&ssz.indices).len());
+        assert!(verify_ssz_multiproof(&root.hash(), &ssz, &BinaryNode::hash_pair).is_ok());
+        // The empty siblings of extensions are zero hashes, like in SSZ
+        assert!(ssz.helpers.contains(&EMPTY_HASH.to_vec()));
+
+        let back = ssz_to_binary(&ssz, &keyvals).unwrap();
+        assert_eq!(back, proof);
+        assert!(BinaryNode::verify_multiproof(&root.hash(), &back).is_ok());
+
+        // A tree made of a single leaf
+        let single = sample_tree(1);
+        let (ssz, keyvals) = binary_to_ssz(&sample_proof(&single, &[0])).unwrap();
+        assert_eq!(ssz.indices, vec![GeneralizedIndex::root()]);
+        assert!(verify_ssz_multiproof(&single.hash(), &ssz, &BinaryNode::hash_pair).is_ok());
+        assert!(ssz_to_binary(&ssz, &keyvals).is_ok());
+    }
+
+    #[test]
+    fn ssz_to_binary_rejects_malformed_proofs() {
+        let root = sample_tree(50);
+        let (ssz, keyvals) = binary_to_ssz(&sample_proof(&root, &[4, 17, 33])).unwrap();
+
+        let mut values = keyvals.clone();
+        values[0].1 = vec![0u8; 32];
+        assert!(ssz_to_binary(&ssz, &values).is_err());
+
+        // Keys and their leaves have to be in the same, sorted, order
+        let mut swapped = ssz.clone();
+        swapped.indices.swap(0, 1);
+        swapped.leaves.swap(0, 1);
+        let mut unsorted = keyvals.clone();
+        unsorted.swap(0, 1);
+        assert!(ssz_to_binary(&swapped, &unsorted).is_err());
+
+        let mut duplicate = ssz.clone();
+        duplicate.indices[1] = duplicate.indices[0].clone();
+        duplicate.leaves[1] = duplicate.leaves[0].clone();
+        let mut dupkeys = keyvals.clone();
+        dupkeys[1] = dupkeys[0].clone();
+        assert!(ssz_to_binary(&duplicate, &dupkeys).is_err());
+
+        // An index that is an ancestor of another one
+        let mut overlapping = ssz.clone();
+        overlapping.indices[0] = GeneralizedIndex::root();
+        overlapping.leaves[0] = Leaf(keyvals[0].0.clone(), keyvals[0].1.clone()).hash();
+        assert!(ssz_to_binary(&overlapping, &keyvals).is_err());
+
+        let mut missing = ssz.clone();
+        missing.helpers.pop();
+        assert!(ssz_to_binary(&missing, &keyvals).is_err());
+        assert!(ssz_to_binary(&ssz, &keyvals[..2]).is_err());
+    }
+}
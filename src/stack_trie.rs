@@ -0,0 +1,328 @@
+use super::store::*;
+use super::*;
+
+// A full node that is still being built, at the depth of the digit
+// that selects its children.
+struct Frame {
+    depth: usize,
+    children: Vec<Node>,
+}
+
+impl Frame {
+    fn new(depth: usize, width: usize) -> Self {
+        Frame {
+            depth,
+            children: vec![Node::EmptySlot; width],
+        }
+    }
+}
+
+// Builds a tree from `(key, value)` pairs that are inserted in strictly
+// increasing key order, and calculates its root hash. The resulting tree
+// is the same as the one built by calling `insert_leaf` on an empty
+// `FullNode` root of the same width.
+//
+// Only the full nodes along the path of the last inserted key are kept
+// in memory, so that it uses O(depth) memory. Each time a subtree is
+// complete, i.e. no subsequent key can be inserted in it, it is replaced
+// by its hash. Complete nodes can optionally be saved to a `NodeStore`.
+pub struct StackTrie<'a> {
+    width: usize,
+    stack: Vec<Frame>,
+    // The last inserted pair, which can only be placed once the next
+    // key is known.
+    pending: Option<(Vec<u8>, Vec<u8>)>,
+    store: Option<&'a mut dyn NodeStore>,
+}
+
+impl<'a> Default for StackTrie<'a> {
+    fn default() -> Self {
+        StackTrie::new()
+    }
+}
+
+impl<'a> StackTrie<'a> {
+    pub fn new() -> Self {
+        StackTrie::with_width(DEFAULT_WIDTH)
+    }
+
+    // Create a builder for a tree whose full nodes have `width` children.
+    // It panics if `width` is lower than 2 or greater than `MAX_WIDTH`.
+    pub fn with_width(width: usize) -> Self {
+        if !(2..=MAX_WIDTH).contains(&width) {
+            panic!("Invalid width {}", width);
+        }
+        StackTrie {
+            width,
+            stack: vec![Frame::new(0, width)],
+            pending: None,
+            store: None,
+        }
+    }
+
+    // Create a builder that saves every node to `store` as soon as it
+    // is complete.
+    pub fn with_store(store: &'a mut dyn NodeStore) -> Self {
+        StackTrie::with_store_and_width(store, DEFAULT_WIDTH)
+    }
+
+    // Same as `with_store`, for a tree whose full nodes have `width`
+    // children.
+    pub fn with_store_and_width(store: &'a mut dyn NodeStore, width: usize) -> Self {
+        let mut trie = StackTrie::with_width(width);
+        trie.store = Some(store);
+        trie
+    }
+
+    // Save a complete node, and return the `Hash` node that replaces it.
+    fn commit(&mut self, node: Node) -> Result<Node, String> {
+        let hash = node.hash(&mut vec![]);
+        if let Some(ref mut store) = self.store {
+            store.put(hash.clone(), encode_node(&node)?)?;
+        }
+        Ok(Node::Hash(hash, 0))
+    }
+
+    // Pop the top frame and replace it with its hash, prefixed by an
+    // extension if its parent is at depth `parent` and there are digits
+    // between the two.
+    fn close_frame(&mut self, key: &[u8], parent: usize) -> Result<Node, String> {
+        let frame = self.stack.pop().unwrap();
+        let node = self.commit(Node::FullNode(Arc::new(frame.children)))?;
+        if frame.depth > parent + 1 {
+            self.commit(Node::Extension(
+                key[parent + 1..frame.depth].to_vec(),
+                Arc::new(node),
+            ))
+        } else {
+            Ok(node)
+        }
+    }
+
+    // Place the pending leaf in its full node, whose depth is given by
+    // the length `common` of the prefix it shares with the next key.
+    // All the full nodes deeper than that are complete and get closed.
+    fn flush(&mut self, common: usize) -> Result<(), String> {
+        let (key, value) = match self.pending.take() {
+            Some(kv) => kv,
+            None => return Ok(()),
+        };
+
+        // The pending leaf is a child of the deepest full node if it is
+        // at least as deep as `common`, and otherwise a new full node at
+        // depth `common` is needed.
+        let top = self.stack.last().unwrap().depth;
+        let parent = std::cmp::max(top, common);
+        let leaf = self.commit(Node::Leaf(
+            NibbleKey::with_width(key[parent + 1..].to_vec(), self.width),
+            value,
+        ))?;
+        self.attach(&key, parent, leaf);
+
+        // Full nodes that are deeper than `common` won't receive any
+        // more keys: replace them with their hash in their parent.
+        while self.stack.last().unwrap().depth > common {
+            let next = self.stack[self.stack.len() - 2].depth;
+            let parent = std::cmp::max(next, common);
+            let child = self.close_frame(&key, parent)?;
+            self.attach(&key, parent, child);
+        }
+
+        Ok(())
+    }
+
+    // Set `child` as the child of the full node at depth `depth` on
+    // the path of `key`, creating that full node if it doesn't exist.
+    fn attach(&mut self, key: &[u8], depth: usize, child: Node) {
+        if self.stack.last().unwrap().depth < depth {
+            self.stack.push(Frame::new(depth, self.width));
+        }
+        self.stack.last_mut().unwrap().children[key[depth] as usize] = child;
+    }
+
+    // Add a `(key, value)` pair to the tree. `key` has to be greater than
+    // the previously inserted key, and none of them can be a prefix of
+    // the other.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String> {
+        if key.is_empty() {
+            return Err("Attempted to insert a 0-byte key".to_string());
+        }
+        if key.iter().any(|d| *d as usize >= self.width) {
+            return Err(format!("Invalid digit in key {:?}", key));
+        }
+
+        let common = match self.pending {
+            Some((ref last, _)) => {
+                if key <= *last {
+                    return Err(format!(
+                        "Keys are not sorted: {:?} inserted after {:?}",
+                        key, last
+                    ));
+                }
+                let common = last
+                    .iter()
+                    .zip(key.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if common == last.len() {
+                    return Err(format!("Key {:?} is a prefix of {:?}", last, key));
+                }
+                common
+            }
+            None => 0,
+        };
+
+        self.flush(common)?;
+        self.pending = Some((key, value));
+        Ok(())
+    }
+
+    // Close all the remaining full nodes, and return the root hash.
+    pub fn finish(mut self) -> Result<Vec<u8>, String> {
+        let key = match self.pending {
+            Some((ref key, _)) => key.clone(),
+            None => vec![],
+        };
+        let top = self.stack.last().unwrap().depth;
+        self.flush(top)?;
+
+        while self.stack.len() > 1 {
+            let parent = self.stack[self.stack.len() - 2].depth;
+            let child = self.close_frame(&key, parent)?;
+            self.attach(&key, parent, child);
+        }
+
+        let root = self.stack.pop().unwrap();
+        match self.commit(Node::FullNode(Arc::new(root.children)))? {
+            Node::Hash(hash, _) => Ok(hash),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Node::*;
+    use super::*;
+    use sha3::{Digest, Keccak256};
+
+    fn hashed_keys(count: usize) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = (0..count)
+            .map(|i| {
+                let mut hasher = Keccak256::new();
+                hasher.input((i as u64).to_be_bytes());
+                NibbleKey::from(ByteKey::from(hasher.result().to_vec())).into()
+            })
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn insert_leaf_root(keyvals: &[(Vec<u8>, Vec<u8>)], width: usize) -> Node {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; width]));
+        for (k, v) in keyvals.iter() {
+            insert_leaf(&mut root, k.clone(), v.clone()).unwrap();
+        }
+        root
+    }
+
+    fn check_against_insert_leaf(keys: Vec<Vec<u8>>, width: usize) {
+        let keyvals: Vec<(Vec<u8>, Vec<u8>)> = keys
+            .into_iter()
+            .enumerate()
+            .map(|(i, k)| (k, vec![i as u8; 32]))
+            .collect();
+
+        let mut builder = StackTrie::with_width(width);
+        for (k, v) in keyvals.iter() {
+            builder.insert(k.clone(), v.clone()).unwrap();
+        }
+        assert_eq!(
+            builder.finish().unwrap(),
+            insert_leaf_root(&keyvals, width).hash(&mut vec![])
+        );
+    }
+
+    #[test]
+    fn empty_stack_trie() {
+        let root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        assert_eq!(StackTrie::new().finish().unwrap(), root.hash(&mut vec![]));
+    }
+
+    #[test]
+    fn single_key() {
+        check_against_insert_leaf(vec![vec![3u8; 32]], DEFAULT_WIDTH);
+    }
+
+    #[test]
+    fn hashed_keys_match_insert_leaf() {
+        check_against_insert_leaf(hashed_keys(300), DEFAULT_WIDTH);
+    }
+
+    #[test]
+    fn shared_prefixes_match_insert_leaf() {
+        let mut keys = vec![];
+        for i in 0..4u8 {
+            for j in 0..3u8 {
+                let mut key = vec![1u8; 16];
+                key[3] = i;
+                key[10 + j as usize] = 7;
+                keys.push(key);
+            }
+        }
+        let mut key = vec![1u8; 16];
+        key[15] = 0;
+        keys.push(key);
+        keys.push(vec![2u8; 16]);
+        keys.sort();
+        keys.dedup();
+        check_against_insert_leaf(keys, DEFAULT_WIDTH);
+    }
+
+    #[test]
+    fn other_widths_match_insert_leaf() {
+        for width in [2usize, 4, 256].iter() {
+            let mut keys: Vec<Vec<u8>> = (0..100u8)
+                .map(|i| {
+                    let mut hasher = Keccak256::new();
+                    hasher.input([i]);
+                    NibbleKey::from_bytes_with_width(&hasher.result()[..4], *width).into()
+                })
+                .collect();
+            keys.sort();
+            keys.dedup();
+            check_against_insert_leaf(keys, *width);
+        }
+
+        let mut builder = StackTrie::with_width(4);
+        assert!(builder.insert(vec![4u8; 8], vec![1u8; 32]).is_err());
+        assert!(builder.insert(vec![3u8; 8], vec![1u8; 32]).is_ok());
+    }
+
+    #[test]
+    fn unsorted_keys_are_rejected() {
+        let mut builder = StackTrie::new();
+        builder.insert(vec![2u8; 32], vec![1u8; 32]).unwrap();
+        assert!(builder.insert(vec![1u8; 32], vec![1u8; 32]).is_err());
+        assert!(builder.insert(vec![2u8; 32], vec![1u8; 32]).is_err());
+        assert!(builder.insert(vec![3u8; 32], vec![1u8; 32]).is_ok());
+    }
+
+    #[test]
+    fn committed_nodes_rebuild_the_tree() {
+        let keys = hashed_keys(50);
+        let keyvals: Vec<(Vec<u8>, Vec<u8>)> = keys.into_iter().map(|k| (k.clone(), k)).collect();
+
+        let mut store = MemoryStore::new();
+        let hash = {
+            let mut builder = StackTrie::with_store(&mut store);
+            for (k, v) in keyvals.iter() {
+                builder.insert(k.clone(), v.clone()).unwrap();
+            }
+            builder.finish().unwrap()
+        };
+
+        let tree = load_tree(&store, &hash).unwrap();
+        assert_eq!(tree, insert_leaf_root(&keyvals, DEFAULT_WIDTH));
+    }
+}
@@ -0,0 +1,280 @@
+use super::account::*;
+use super::iter::Entry;
+use super::secure_trie::*;
+use super::world_state::WorldState;
+use super::*;
+use std::collections::{BTreeMap, HashSet};
+
+// A proof of a set of accounts, along with some slots of their storage
+// and, optionally, their contract code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateProof {
+    pub accounts: Multiproof,                  // Proof over the account tree
+    pub storage: Vec<(NibbleKey, Multiproof)>, // Proofs over the storage trees, by hashed address
+    pub code: Vec<(Vec<u8>, Vec<u8>)>,         // Contract code of proven accounts, by code hash
+}
+
+impl StateProof {
+    // Attach contract code to the proof. It has to be the code of one of
+    // the proven accounts for the proof to be valid.
+    pub fn attach_code(&mut self, code: Vec<u8>) {
+        let hash = code_hash(&code);
+        if let Err(index) = self.code.binary_search_by(|(h, _)| h.cmp(&hash)) {
+            self.code.insert(index, (hash, code));
+        }
+    }
+}
+
+// An account whose proof has been checked, along with its proven storage
+// slots, indexed by the hash of the slot, and its code if it was attached
+// to the proof.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProvenAccount {
+    pub account: Account,
+    pub storage: Vec<(NibbleKey, Vec<u8>)>,
+    pub code: Option<Vec<u8>>,
+}
+
+impl WorldState {
+    // Build a proof of the accounts in `touched`, each of them with the
+    // list of its storage slots that need to be proven.
+    pub fn prove(&self, touched: &[(ByteKey, Vec<ByteKey>)]) -> Result<StateProof, String> {
+        // Merge the slots of accounts that appear several times
+        let mut slots: BTreeMap<NibbleKey, (&ByteKey, Vec<&ByteKey>)> = BTreeMap::new();
+        for (address, keys) in touched.iter() {
+            let entry = slots
+                .entry(hash_key(address))
+                .or_insert_with(|| (address, Vec::new()));
+            entry.1.extend(keys.iter());
+        }
+
+        let accounts = self
+            .accounts()
+            .prove_accounts(slots.values().map(|(address, _)| *address))?;
+        let mut storage = Vec::new();
+        for (hashed, (address, keys)) in slots.into_iter() {
+            if keys.is_empty() {
+                continue;
+            }
+            let trie = self
+                .storage_trie(address)
+                .ok_or_else(|| format!("Account {:?} has no storage", address))?;
+            storage.push((hashed, trie.prove(keys)?));
+        }
+
+        Ok(StateProof {
+            accounts,
+            storage,
+            code: Vec::new(),
+        })
+    }
+}
+
+// Check a `StateProof` against the state root: the storage proofs are
+// checked against the `storage_root` of their account, and the code is
+// checked against the `code_hash` of the accounts it belongs to. The proven
+// accounts are returned along with their hashed address, in hashed
+// address order.
+pub fn verify_state_proof(
+    root_hash: &[u8],
+    proof: &StateProof,
+) -> Result<Vec<(NibbleKey, ProvenAccount)>, String> {
+    let mut accounts: BTreeMap<NibbleKey, ProvenAccount> =
+        verify_accounts(root_hash, &proof.accounts)?
+            .into_iter()
+            .map(|(key, account)| {
+                (
+                    key,
+                    ProvenAccount {
+                        account,
+                        storage: Vec::new(),
+                        code: None,
+                    },
+                )
+            })
+            .collect();
+
+    // A storage proof can prove no slot, so duplicates are detected by
+    // address rather than by looking at the slots already proven.
+    let mut seen = HashSet::new();
+    for (hashed, storage_proof) in proof.storage.iter() {
+        let proven = accounts
+            .get_mut(hashed)
+            .ok_or_else(|| format!("Storage proof for unproven account {:?}", hashed))?;
+        if !seen.insert(hashed) {
+            return Err(format!("Duplicate storage proof for account {:?}", hashed));
+        }
+        let root = verify_multiproof(&proven.account.storage_root, storage_proof)?;
+        for entry in root.iter() {
+            if let Entry::Leaf(key, value) = entry {
+                proven.storage.push((key, value.to_vec()));
+            }
+        }
+    }
+
+    for (hash, code) in proof.code.iter() {
+        if code_hash(code) != *hash {
+            return Err(format!("Code doesn't match its hash {:?}", hash));
+        }
+        let mut found = false;
+        for proven in accounts.values_mut() {
+            if proven.account.code_hash == *hash {
+                if proven.code.is_some() {
+                    return Err(format!("Duplicate code for hash {:?}", hash));
+                }
+                proven.code = Some(code.clone());
+                found = true;
+            }
+        }
+        if !found {
+            return Err(format!(
+                "Code {:?} doesn't belong to any proven account",
+                hash
+            ));
+        }
+    }
+
+    Ok(accounts.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(i: u8) -> ByteKey {
+        ByteKey::from(vec![i; 20])
+    }
+
+    fn slot(i: u8) -> ByteKey {
+        ByteKey::from(vec![i; 32])
+    }
+
+    fn sample_state() -> WorldState {
+        let mut state = WorldState::new();
+        for i in 0..10u8 {
+            let account = Account {
+                nonce: i as u64,
+                ..Account::default()
+            };
+            state.insert_account(&address(i), account).unwrap();
+            for j in 0..i {
+                state
+                    .insert_storage(&address(i), &slot(j), vec![i, j])
+                    .unwrap();
+            }
+        }
+        state
+    }
+
+    #[test]
+    fn prove_and_verify_state() {
+        let state = sample_state();
+        let touched = vec![
+            (address(2), vec![]),
+            (address(7), vec![slot(3), slot(5)]),
+            (address(4), vec![slot(0)]),
+            (address(7), vec![slot(1)]),
+        ];
+        let proof = state.prove(&touched).unwrap();
+        assert_eq!(proof.storage.len(), 2);
+
+        let proven = verify_state_proof(&state.root_hash(), &proof).unwrap();
+        assert_eq!(proven.len(), 3);
+        let (_, seven) = proven
+            .iter()
+            .find(|(k, _)| *k == hash_key(&address(7)))
+            .unwrap();
+        assert_eq!(seven.account.nonce, 7);
+        assert_eq!(seven.storage.len(), 3);
+        for j in [1u8, 3, 5].iter() {
+            assert!(seven.storage.contains(&(hash_key(&slot(*j)), vec![7, *j])));
+        }
+    }
+
+    #[test]
+    fn storage_proof_must_match_account() {
+        let state = sample_state();
+        let mut proof = state
+            .prove(&[(address(5), vec![slot(1)]), (address(6), vec![slot(1)])])
+            .unwrap();
+
+        // Swap the storage proofs of both accounts
+        let first = proof.storage[0].1.clone();
+        proof.storage[0].1 = proof.storage[1].1.clone();
+        proof.storage[1].1 = first;
+        assert!(verify_state_proof(&state.root_hash(), &proof).is_err());
+
+        // Storage proof for an account that isn't in the proof
+        let mut proof = state.prove(&[(address(5), vec![slot(1)])]).unwrap();
+        proof.storage[0].0 = hash_key(&address(6));
+        assert!(verify_state_proof(&state.root_hash(), &proof).is_err());
+    }
+
+    #[test]
+    fn duplicate_storage_proof() {
+        let state = sample_state();
+        let mut proof = state.prove(&[(address(5), vec![slot(1)])]).unwrap();
+        let duplicate = proof.storage[0].clone();
+        proof.storage.push(duplicate);
+        assert!(verify_state_proof(&state.root_hash(), &proof).is_err());
+
+        // A first proof that doesn't prove any slot
+        let root = state.storage_trie(&address(5)).unwrap().root_hash();
+        let empty = Multiproof {
+            hashes: vec![root],
+            instructions: vec![Instruction::HASHER(0)],
+            keyvals: vec![],
+        };
+        let mut proof = state.prove(&[(address(5), vec![slot(1)])]).unwrap();
+        proof.storage.insert(0, (hash_key(&address(5)), empty));
+        assert!(verify_state_proof(&state.root_hash(), &proof).is_err());
+        proof.storage.pop();
+        let proven = verify_state_proof(&state.root_hash(), &proof).unwrap();
+        assert!(proven[0].1.storage.is_empty());
+    }
+
+    #[test]
+    fn prove_and_verify_code() {
+        let mut state = sample_state();
+        state.insert_code(&address(3), vec![0x60, 0x01]).unwrap();
+        state.insert_code(&address(4), vec![0x60, 0x01]).unwrap();
+        state.insert_code(&address(5), vec![0x60, 0x02]).unwrap();
+
+        let mut proof = state
+            .prove(&[
+                (address(3), vec![]),
+                (address(4), vec![]),
+                (address(6), vec![]),
+            ])
+            .unwrap();
+        let code = state.code(&address(3)).unwrap().unwrap();
+        proof.attach_code(code.clone());
+        proof.attach_code(code.clone());
+        assert_eq!(proof.code.len(), 1);
+
+        let proven = verify_state_proof(&state.root_hash(), &proof).unwrap();
+        for (hashed, account) in proven.iter() {
+            if *hashed == hash_key(&address(6)) {
+                assert_eq!(account.code, None);
+            } else {
+                assert_eq!(account.code, Some(code.clone()));
+            }
+        }
+
+        // Code that doesn't belong to a proven account
+        let mut extra = proof.clone();
+        extra.attach_code(state.code(&address(5)).unwrap().unwrap());
+        assert!(verify_state_proof(&state.root_hash(), &extra).is_err());
+
+        // Code that doesn't match its hash
+        proof.code[0].1 = vec![0x60, 0x02];
+        assert!(verify_state_proof(&state.root_hash(), &proof).is_err());
+    }
+
+    #[test]
+    fn prove_missing_storage() {
+        let state = sample_state();
+        assert!(state.prove(&[(address(0), vec![slot(0)])]).is_err());
+        assert!(state.prove(&[(address(3), vec![slot(5)])]).is_err());
+    }
+}
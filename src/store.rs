@@ -0,0 +1,466 @@
+use super::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Storage encoding tags, used to tell node types apart when decoding.
+const LEAF_TAG: u8 = 0;
+const EXTENSION_TAG: u8 = 1;
+const FULLNODE_TAG: u8 = 2;
+
+// A `NodeStore` maps the hash of a node, as returned by `Node::hash`,
+// to its storage encoding. Children are referenced by their hash, so
+// that a tree can be loaded one level at a time.
+pub trait NodeStore {
+    // Get the storage encoding of the node with hash `hash`, if it is
+    // present in the store.
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    // Save the storage encoding of a node under its hash.
+    fn put(&mut self, hash: Vec<u8>, encoding: Vec<u8>) -> Result<(), String>;
+
+    // Delete the node with hash `hash` from the store.
+    fn remove(&mut self, hash: &[u8]) -> Result<(), String>;
+
+    // List the hashes of all the nodes in the store.
+    fn hashes(&self) -> Vec<Vec<u8>>;
+}
+
+// A simple store that keeps all its nodes in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStore(HashMap<Vec<u8>, Vec<u8>>);
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore(HashMap::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl NodeStore for MemoryStore {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.0.get(hash).cloned())
+    }
+
+    fn put(&mut self, hash: Vec<u8>, encoding: Vec<u8>) -> Result<(), String> {
+        self.0.insert(hash, encoding);
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &[u8]) -> Result<(), String> {
+        self.0.remove(hash);
+        Ok(())
+    }
+
+    fn hashes(&self) -> Vec<Vec<u8>> {
+        self.0.keys().cloned().collect()
+    }
+}
+
+impl Resolver for MemoryStore {
+    fn resolve(&self, hash: &[u8]) -> Result<Node, String> {
+        load_node(self, hash)
+    }
+}
+
+// Encode a node for storage. The first item of the list is a tag
+// describing the type of the node, and children are replaced with
+// their hash. `EmptySlot` children are represented by an empty hash.
+pub fn encode_node(node: &Node) -> Result<Vec<u8>, String> {
+    use Node::*;
+
+    match node {
+        Leaf(key, value) => {
+            let mut stream = rlp::RlpStream::new_list(3);
+            let key: Vec<u8> = key.clone().into();
+            stream.append(&LEAF_TAG).append(&key).append(value);
+            Ok(stream.out())
+        }
+        Extension(ext, child) => {
+            let mut stream = rlp::RlpStream::new_list(3);
+            stream
+                .append(&EXTENSION_TAG)
+                .append(ext)
+                .append(&child.hash(&mut vec![]));
+            Ok(stream.out())
+        }
+        FullNode(children) => {
+            let mut stream = rlp::RlpStream::new_list(children.len() + 1);
+            stream.append(&FULLNODE_TAG);
+            for child in children.iter() {
+                stream.append(&child.hash(&mut vec![]));
+            }
+            Ok(stream.out())
+        }
+        Hash(_, _) => Err("Can not encode the content of a hashed node".to_string()),
+        EmptySlot => Err("Can not encode an empty slot".to_string()),
+    }
+}
+
+// Decode a node from its storage encoding. Children are returned as
+// `Hash` nodes, that can then be loaded from the store.
+pub fn decode_node(encoding: &[u8]) -> Result<Node, String> {
+    use Node::*;
+
+    let r = rlp::Rlp::new(encoding);
+    let count = r.item_count().map_err(|e| format!("{:?}", e))?;
+    let tag = r.val_at::<u8>(0).map_err(|e| format!("{:?}", e))?;
+    let item = |i: usize| r.val_at::<Vec<u8>>(i).map_err(|e| format!("{:?}", e));
+    let child = |h: Vec<u8>| if h.is_empty() { EmptySlot } else { Hash(h, 0) };
+
+    match (tag, count) {
+        (LEAF_TAG, 3) => Ok(Leaf(NibbleKey::from_digits_unchecked(item(1)?), item(2)?)),
+        (EXTENSION_TAG, 3) => Ok(Extension(item(1)?, Arc::new(child(item(2)?)))),
+        (FULLNODE_TAG, n) if n > 1 => {
+            let mut children = Vec::with_capacity(n - 1);
+            for i in 1..n {
+                children.push(child(item(i)?));
+            }
+            Ok(FullNode(Arc::new(children)))
+        }
+        _ => Err(format!(
+            "Invalid storage encoding: tag {} with {} items",
+            tag, count
+        )),
+    }
+}
+
+// Save all the nodes of the (sub-)tree rooted at `root` into `store`,
+// and return the hash of the root. Subtrees whose root is already in
+// the store are skipped, as are `Hash` nodes whose content is unknown.
+pub fn store_tree<S: NodeStore>(store: &mut S, root: &Node) -> Result<Vec<u8>, String> {
+    use Node::*;
+
+    let hash = root.hash(&mut vec![]);
+    match root {
+        Hash(_, _) | EmptySlot => return Ok(hash),
+        _ => {
+            if store.get(&hash)?.is_some() {
+                return Ok(hash);
+            }
+        }
+    }
+
+    match root {
+        Extension(_, child) => {
+            store_tree(store, child)?;
+        }
+        FullNode(children) => {
+            for child in children.iter() {
+                store_tree(store, child)?;
+            }
+        }
+        _ => {}
+    }
+    store.put(hash.clone(), encode_node(root)?)?;
+
+    Ok(hash)
+}
+
+// Load the node whose hash is `hash` from the store. Its children are
+// left as `Hash` nodes.
+pub fn load_node<S: NodeStore>(store: &S, hash: &[u8]) -> Result<Node, String> {
+    match store.get(hash)? {
+        Some(encoding) => decode_node(&encoding),
+        None => Err(format!("Node {:?} not found in store", hash)),
+    }
+}
+
+// Load the whole (sub-)tree whose root hash is `hash` from the store.
+// Nodes that are missing from the store are kept as `Hash` nodes.
+pub fn load_tree<S: NodeStore>(store: &S, hash: &[u8]) -> Result<Node, String> {
+    use Node::*;
+
+    let node = match store.get(hash)? {
+        Some(encoding) => decode_node(&encoding)?,
+        None => return Ok(Hash(hash.to_vec(), 0)),
+    };
+
+    match node {
+        Extension(ext, child) => match &*child {
+            Hash(h, _) => Ok(Extension(ext, Arc::new(load_tree(store, h)?))),
+            _ => Ok(Extension(ext, child)),
+        },
+        FullNode(children) => {
+            let mut loaded = Vec::with_capacity(children.len());
+            for child in children.iter() {
+                loaded.push(match child {
+                    Hash(h, _) => load_tree(store, h)?,
+                    other => other.clone(),
+                });
+            }
+            Ok(FullNode(Arc::new(loaded)))
+        }
+        other => Ok(other),
+    }
+}
+
+// Mark all the nodes that are reachable from `hash` and present in
+// the store.
+fn mark<S: NodeStore>(store: &S, hash: &[u8], live: &mut HashSet<Vec<u8>>) -> Result<(), String> {
+    use Node::*;
+
+    if live.contains(hash) {
+        return Ok(());
+    }
+    let node = match store.get(hash)? {
+        Some(encoding) => decode_node(&encoding)?,
+        None => return Ok(()),
+    };
+    live.insert(hash.to_vec());
+
+    match node {
+        Extension(_, child) => {
+            if let Hash(ref h, _) = *child {
+                mark(store, h, live)?;
+            }
+        }
+        FullNode(children) => {
+            for child in children.iter() {
+                if let Hash(h, _) = child {
+                    mark(store, h, live)?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Delete every node of the store that can not be reached from one of
+// the roots in `live_roots`, and return the number of deleted nodes.
+pub fn prune<S: NodeStore>(store: &mut S, live_roots: &[Vec<u8>]) -> Result<usize, String> {
+    let mut live = HashSet::new();
+    for root in live_roots.iter() {
+        mark(store, root, &mut live)?;
+    }
+
+    let mut count = 0;
+    for hash in store.hashes() {
+        if !live.contains(&hash) {
+            store.remove(&hash)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Keeps track of the most recent roots that have been committed to a
+// store, e.g. one per block, and prunes the nodes that are no longer
+// reachable from any of them.
+#[derive(Debug)]
+pub struct RootHistory {
+    retain: usize,
+    roots: VecDeque<Vec<u8>>,
+}
+
+impl RootHistory {
+    // Create a history that keeps the `retain` most recent roots.
+    pub fn new(retain: usize) -> Self {
+        RootHistory::from_roots(retain, &[])
+    }
+
+    // Create a history from a list of previously committed roots, the
+    // most recent one being last.
+    pub fn from_roots(retain: usize, roots: &[Vec<u8>]) -> Self {
+        if retain == 0 {
+            panic!("At least one root needs to be retained");
+        }
+        let skip = roots.len().saturating_sub(retain);
+        RootHistory {
+            retain,
+            roots: roots[skip..].iter().cloned().collect(),
+        }
+    }
+
+    // The retained roots, from the oldest to the most recent one.
+    pub fn roots(&self) -> Vec<Vec<u8>> {
+        self.roots.iter().cloned().collect()
+    }
+
+    // Record `root` as the most recent root. If this pushes older roots
+    // out of the history, the nodes that are only reachable from them
+    // are deleted from `store`. Returns the number of deleted nodes.
+    pub fn commit<S: NodeStore>(&mut self, store: &mut S, root: Vec<u8>) -> Result<usize, String> {
+        self.roots.push_back(root);
+        if self.roots.len() <= self.retain {
+            return Ok(0);
+        }
+        while self.roots.len() > self.retain {
+            self.roots.pop_front();
+        }
+        prune(store, &self.roots())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Node::*;
+    use super::*;
+
+    fn sample_tree() -> Node {
+        let mut root = FullNode(Arc::new(vec![EmptySlot; 16]));
+        insert_leaf(&mut root, vec![2u8; 32], vec![0u8; 32]).unwrap();
+        insert_leaf(&mut root, vec![1u8; 32], vec![1u8; 32]).unwrap();
+        insert_leaf(&mut root, vec![8u8; 32], vec![150u8; 32]).unwrap();
+        let mut key = vec![1u8; 32];
+        key[5] = 3;
+        insert_leaf(&mut root, key, vec![3u8; 32]).unwrap();
+        root
+    }
+
+    #[test]
+    fn encode_decode_leaf() {
+        let leaf = Leaf(NibbleKey::new(vec![1, 2, 3]), vec![4, 5, 6]);
+        let encoding = encode_node(&leaf).unwrap();
+        assert_eq!(decode_node(&encoding).unwrap(), leaf);
+    }
+
+    #[test]
+    fn decode_invalid_tag() {
+        let mut stream = rlp::RlpStream::new_list(2);
+        stream.append(&7u8).append(&vec![1u8]);
+        assert!(decode_node(&stream.out()).is_err());
+    }
+
+    #[test]
+    fn store_and_load_tree() {
+        let root = sample_tree();
+        let mut store = MemoryStore::new();
+        let hash = store_tree(&mut store, &root).unwrap();
+        assert_eq!(hash, root.hash(&mut vec![]));
+
+        let loaded = load_tree(&store, &hash).unwrap();
+        assert_eq!(loaded, root);
+    }
+
+    #[test]
+    fn load_single_level() {
+        let root = sample_tree();
+        let mut store = MemoryStore::new();
+        let hash = store_tree(&mut store, &root).unwrap();
+
+        match load_node(&store, &hash).unwrap() {
+            FullNode(children) => {
+                assert_eq!(children[0], EmptySlot);
+                assert_eq!(children[1], Hash(root_child(&root, 1).hash(&mut vec![]), 0));
+            }
+            n => panic!("Invalid root node {:?}", n),
+        }
+    }
+
+    #[test]
+    fn load_missing_node() {
+        let store = MemoryStore::new();
+        assert!(load_node(&store, &[0u8; 32]).is_err());
+        assert_eq!(
+            load_tree(&store, &[0u8; 32]).unwrap(),
+            Hash(vec![0u8; 32], 0)
+        );
+    }
+
+    #[test]
+    fn insert_into_partially_loaded_tree() {
+        let mut full = sample_tree();
+        let mut store = MemoryStore::new();
+        let hash = store_tree(&mut store, &full).unwrap();
+
+        let mut key = vec![1u8; 32];
+        key[7] = 4;
+        let mut partial = load_node(&store, &hash).unwrap();
+        let out =
+            insert_leaf_with_resolver(&mut partial, key.clone(), vec![5u8; 32], &store).unwrap();
+        let expected = insert_leaf(&mut full, key.clone(), vec![5u8; 32]).unwrap();
+        assert_eq!(out.hash(&mut vec![]), expected.hash(&mut vec![]));
+        assert_eq!(
+            get_with_resolver(&out, &key, &store).unwrap(),
+            Some(vec![5u8; 32])
+        );
+
+        // Without a resolver, the insertion fails instead of panicking
+        let mut partial = load_node(&store, &hash).unwrap();
+        assert!(insert_leaf(&mut partial, key, vec![5u8; 32]).is_err());
+    }
+
+    #[test]
+    fn multiproof_of_partially_loaded_tree() {
+        let full = sample_tree();
+        let mut store = MemoryStore::new();
+        let hash = store_tree(&mut store, &full).unwrap();
+        let partial = load_node(&store, &hash).unwrap();
+
+        let keyvals = vec![(vec![2u8; 32], vec![0u8; 32])];
+        assert!(make_multiproof(&partial, keyvals.clone()).is_err());
+        let proof = make_multiproof_with_resolver(&partial, keyvals.clone(), &store).unwrap();
+        let expected = make_multiproof(&full, keyvals).unwrap();
+        assert_eq!(proof.hashes, expected.hashes);
+        assert_eq!(proof.keyvals, expected.keyvals);
+    }
+
+    #[test]
+    fn resolver_returning_the_wrong_node() {
+        let full = sample_tree();
+        let mut store = MemoryStore::new();
+        let hash = store_tree(&mut store, &full).unwrap();
+        let partial = load_node(&store, &hash).unwrap();
+
+        let liar = |_: &[u8]| Ok(Leaf(NibbleKey::new(vec![2u8; 31]), vec![9u8; 32]));
+        assert!(get_with_resolver(&partial, &[2u8; 32], &liar).is_err());
+        assert_eq!(
+            get_with_resolver(&partial, &[2u8; 32], &store).unwrap(),
+            Some(vec![0u8; 32])
+        );
+    }
+
+    #[test]
+    fn prune_keeps_retained_roots() {
+        let mut root = sample_tree();
+        let mut store = MemoryStore::new();
+        let mut history = RootHistory::new(2);
+        let mut hashes = vec![];
+        for i in 0..3u8 {
+            let mut key = vec![1u8; 32];
+            key[10] = i + 5;
+            insert_leaf(&mut root, key, vec![i; 32]).unwrap();
+            let hash = store_tree(&mut store, &root).unwrap();
+            hashes.push((hash.clone(), root.clone()));
+            let deleted = history.commit(&mut store, hash).unwrap();
+            assert_eq!(deleted > 0, i == 2);
+        }
+
+        assert_eq!(
+            history.roots(),
+            vec![hashes[1].0.clone(), hashes[2].0.clone()]
+        );
+        assert!(store.get(&hashes[0].0).unwrap().is_none());
+        for (hash, tree) in hashes[1..].iter() {
+            assert_eq!(load_tree(&store, hash).unwrap(), *tree);
+        }
+
+        // Everything left in the store is reachable from a live root
+        let before = store.len();
+        assert_eq!(prune(&mut store, &history.roots()).unwrap(), 0);
+        assert_eq!(store.len(), before);
+    }
+
+    #[test]
+    fn prune_everything() {
+        let mut store = MemoryStore::new();
+        store_tree(&mut store, &sample_tree()).unwrap();
+        let count = store.len();
+        assert_eq!(prune(&mut store, &[]).unwrap(), count);
+        assert!(store.is_empty());
+    }
+
+    fn root_child(root: &Node, index: usize) -> &Node {
+        match root {
+            FullNode(children) => &children[index],
+            _ => panic!("Root is not a full node"),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use super::binary::*;
+use super::iter::Entry;
+use super::*;
+
+// Convert a nibble key into the equivalent bit key.
+pub fn nibbles_to_bits(nibbles: &[u8]) -> Vec<u8> {
+    let mut bits = Vec::with_capacity(nibbles.len() * 4);
+    for nibble in nibbles.iter() {
+        for i in (0..4).rev() {
+            bits.push((nibble >> i) & 1);
+        }
+    }
+    bits
+}
+
+// Build the binary tree holding the same `(key, value)` pairs as the
+// hexary tree `root`. An error is returned if `root` contains `Hash`
+// nodes, since their content can not be converted.
+pub fn hex_to_binary(root: &Node) -> Result<BinaryNode, String> {
+    let mut binary = BinaryNode::EmptySlot;
+    for entry in root.iter() {
+        match entry {
+            Entry::Leaf(key, value) => {
+                let key: Vec<u8> = key.into();
+                binary = binary.insert(&nibbles_to_bits(&key), value.to_vec())?;
+            }
+            Entry::Hash(prefix, _) => {
+                return Err(format!("Can not convert hashed subtree at {:?}", prefix))
+            }
+        }
+    }
+    Ok(binary)
+}
+
+// Estimate the size of a proof in bytes, with keys packed at
+// `bits_per_digit` bits per key digit: 4 for a hexary proof and 1 for a
+// binary one. Each instruction takes one byte for its opcode and one for
+// its operand, and `EXTENSION` is followed by its packed key.
+pub fn witness_size(proof: &Multiproof, bits_per_digit: usize) -> Result<usize, String> {
+    let packed = |digits: usize| (digits * bits_per_digit).div_ceil(8);
+
+    let mut size: usize = proof.hashes.iter().map(|h| h.len()).sum();
+    for encoded in proof.keyvals.iter() {
+        let keyval = rlp::Rlp::new(encoded)
+            .as_list::<Vec<u8>>()
+            .map_err(|e| format!("Invalid (key,value) pair: {:?}", e))?;
+        if keyval.len() != 2 {
+            return Err(format!("Invalid (key,value) pair: {:?}", keyval));
+        }
+        size += packed(keyval[0].len()) + keyval[1].len();
+    }
+    for instr in proof.instructions.iter() {
+        size += 2;
+        if let Instruction::EXTENSION(ext) = instr {
+            size += packed(ext.len());
+        }
+    }
+    Ok(size)
+}
+
+// The result of converting a hexary tree into a binary one, and of
+// proving the same sample of keys in both.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionReport {
+    pub hex_root: Vec<u8>,
+    pub binary_root: Vec<u8>,
+    pub hex_witness_size: usize,
+    pub binary_witness_size: usize,
+}
+
+impl TransitionReport {
+    // The change in witness size when switching to the binary tree, in
+    // bytes. It is negative if the binary witness is smaller.
+    pub fn witness_size_delta(&self) -> isize {
+        self.binary_witness_size as isize - self.hex_witness_size as isize
+    }
+}
+
+// Convert `root` into a binary tree, and compare the witnesses of both
+// trees for the keys in `sample`, which must all be present in `root`.
+pub fn transition_report(
+    root: &Node,
+    sample: &[Vec<u8>],
+) -> Result<(BinaryNode, TransitionReport), String> {
+    let binary = hex_to_binary(root)?;
+
+    let mut keyvals = Vec::new();
+    for key in sample.iter() {
+        match get(root, key)? {
+            Some(value) => keyvals.push((key.to_vec(), value)),
+            None => return Err(format!("Key {:?} isn't present in the tree", key)),
+        }
+    }
+    keyvals.sort();
+    keyvals.dedup();
+    let binary_keyvals = keyvals
+        .iter()
+        .map(|(k, v)| (nibbles_to_bits(k), v.to_vec()))
+        .collect();
+
+    let hex_proof = make_multiproof(root, keyvals)?;
+    let binary_proof = binary.make_multiproof(binary_keyvals)?;
+    let report = TransitionReport {
+        hex_root: root.hash(&mut vec![]),
+        binary_root: binary.hash(),
+        hex_witness_size: witness_size(&hex_proof, 4)?,
+        binary_witness_size: witness_size(&binary_proof, 1)?,
+    };
+    Ok((binary, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashed_key(i: u16) -> Vec<u8> {
+        let mut hasher = Keccak256::new();
+        hasher.input(i.to_be_bytes());
+        NibbleKey::from(ByteKey::from(hasher.result().to_vec())).into()
+    }
+
+    fn sample_tree(count: u16) -> Node {
+        let mut root = Node::FullNode(Arc::new(vec![Node::EmptySlot; 16]));
+        for i in 0..count {
+            insert_leaf(&mut root, hashed_key(i), vec![i as u8; 32]).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn nibbles_to_bits_matches_bytes() {
+        let nibbles: Vec<u8> = NibbleKey::from(ByteKey::from(vec![0xa5, 0x3c])).into();
+        assert_eq!(nibbles_to_bits(&nibbles), bytes_to_bits(&[0xa5, 0x3c]));
+    }
+
+    #[test]
+    fn conversion_keeps_keyvals() {
+        let root = sample_tree(100);
+        let binary = hex_to_binary(&root).unwrap();
+        for i in 0..100u16 {
+            assert_eq!(
+                binary.get(&nibbles_to_bits(&hashed_key(i))).unwrap(),
+                Some(vec![i as u8; 32])
+            );
+        }
+
+        let mut expected = BinaryNode::EmptySlot;
+        for i in (0..100u16).rev() {
+            expected = expected
+                .insert(&nibbles_to_bits(&hashed_key(i)), vec![i as u8; 32])
+                .unwrap();
+        }
+        assert_eq!(binary.hash(), expected.hash());
+    }
+
+    #[test]
+    fn conversion_rejects_hashed_subtrees() {
+        let mut children = vec![Node::EmptySlot; 16];
+        children[3] = Node::Hash(vec![1u8; 32], 0);
+        assert!(hex_to_binary(&Node::FullNode(Arc::new(children))).is_err());
+    }
+
+    #[test]
+    fn report_witness_sizes() {
+        let root = sample_tree(200);
+        let sample: Vec<Vec<u8>> = (0..10u16).map(hashed_key).collect();
+        let (binary, report) = transition_report(&root, &sample).unwrap();
+
+        assert_eq!(report.hex_root, root.hash(&mut vec![]));
+        assert_eq!(report.binary_root, binary.hash());
+        assert!(report.hex_witness_size > 0);
+        assert!(report.binary_witness_size > 0);
+        assert_eq!(
+            report.witness_size_delta(),
+            report.binary_witness_size as isize - report.hex_witness_size as isize
+        );
+
+        assert!(transition_report(&root, &[hashed_key(500)]).is_err());
+    }
+
+    #[test]
+    fn witness_size_of_single_leaf() {
+        let proof = Multiproof {
+            hashes: vec![vec![0u8; 32]],
+            instructions: vec![
+                Instruction::HASHER(0),
+                Instruction::LEAF(3),
+                Instruction::EXTENSION(vec![1, 2, 3]),
+            ],
+            keyvals: vec![rlp::encode(&Node::Leaf(
+                NibbleKey::new(vec![1, 2, 3]),
+                vec![7u8; 4],
+            ))],
+        };
+        // 32 for the hash, 2 + 4 for the leaf key and value, 3 opcodes of
+        // 2 bytes each, and 2 bytes of extension key
+        assert_eq!(witness_size(&proof, 4).unwrap(), 46);
+    }
+}
@@ -0,0 +1,299 @@
+use super::*;
+use std::iter::FromIterator;
+
+// A tree of `(key, value)` pairs. It owns its root, so that callers
+// never have to keep track of which node is the current root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trie {
+    root: Node,
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Trie::new()
+    }
+}
+
+impl Trie {
+    // Create an empty tree.
+    pub fn new() -> Self {
+        Trie::with_width(DEFAULT_WIDTH)
+    }
+
+    // Create an empty tree whose full nodes have `width` children. It
+    // panics if `width` is lower than 2 or greater than `MAX_WIDTH`.
+    pub fn with_width(width: usize) -> Self {
+        if !(2..=MAX_WIDTH).contains(&width) {
+            panic!("Invalid width {}", width);
+        }
+        Trie {
+            root: Node::FullNode(Arc::new(vec![Node::EmptySlot; width])),
+        }
+    }
+
+    // Create a tree from an existing root, e.g. the output of `rebuild`.
+    pub fn from_root(root: Node) -> Self {
+        Trie { root }
+    }
+
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    pub fn root_hash(&self) -> Vec<u8> {
+        self.root.hash(&mut vec![])
+    }
+
+    // Get the value associated to `key`, if any.
+    pub fn get<K: Into<NibbleKey>>(&self, key: K) -> Result<Option<Vec<u8>>, String> {
+        let key: Vec<u8> = key.into().into();
+        get(&self.root, &key)
+    }
+
+    // Associate `value` to `key`, and return the previous value if the
+    // key was already present.
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> Result<Option<Vec<u8>>, String>
+    where
+        K: Into<NibbleKey>,
+        V: Into<Vec<u8>>,
+    {
+        let key: Vec<u8> = key.into().into();
+        let previous = get(&self.root, &key)?;
+        if previous.is_some() {
+            self.root = remove_leaf(&self.root, &key)?;
+        }
+        self.root = insert_leaf(&mut self.root, key, value.into())?;
+        Ok(previous)
+    }
+
+    // Remove `key` from the tree, and return its value if it was present.
+    pub fn remove<K: Into<NibbleKey>>(&mut self, key: K) -> Result<Option<Vec<u8>>, String> {
+        let key: Vec<u8> = key.into().into();
+        let previous = get(&self.root, &key)?;
+        if previous.is_some() {
+            self.root = remove_leaf(&self.root, &key)?;
+        }
+        Ok(previous)
+    }
+
+    // Build a multiproof of the current values of `keys`.
+    pub fn prove<K, I>(&self, keys: I) -> Result<Multiproof, String>
+    where
+        K: Into<NibbleKey>,
+        I: IntoIterator<Item = K>,
+    {
+        let mut keyvals = Vec::new();
+        for key in keys {
+            let key: Vec<u8> = key.into().into();
+            match get(&self.root, &key)? {
+                Some(value) => keyvals.push((key, value)),
+                None => return Err(format!("Key {:?} isn't present in the tree", key)),
+            }
+        }
+        keyvals.sort();
+        keyvals.dedup();
+        make_multiproof(&self.root, keyvals)
+    }
+}
+
+// Build a tree from a list of `(key, value)` pairs. It panics if one of
+// the keys is invalid, e.g. empty.
+impl<K, V> FromIterator<(K, V)> for Trie
+where
+    K: Into<NibbleKey>,
+    V: Into<Vec<u8>>,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut trie = Trie::new();
+        for (key, value) in iter {
+            trie.insert(key, value).unwrap();
+        }
+        trie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(first: u8, rest: u8) -> NibbleKey {
+        let mut key = vec![rest; 32];
+        key[0] = first;
+        NibbleKey::new(key)
+    }
+
+    fn sample() -> Vec<(NibbleKey, Vec<u8>)> {
+        let mut keyvals = vec![];
+        for (i, first) in [1u8, 2, 8, 15].iter().enumerate() {
+            for (j, rest) in [0u8, 3, 9].iter().enumerate() {
+                keyvals.push((key(*first, *rest), vec![(3 * i + j) as u8; 32]));
+            }
+        }
+        // Create an extension below a full node
+        let mut long = vec![4u8; 32];
+        long[30] = 2;
+        keyvals.push((NibbleKey::new(long), vec![100u8; 32]));
+        keyvals.push((key(4, 4), vec![101u8; 32]));
+        keyvals
+    }
+
+    #[test]
+    fn from_iter_and_get() {
+        let keyvals = sample();
+        let trie: Trie = keyvals.clone().into_iter().collect();
+        for (k, v) in keyvals.iter() {
+            assert_eq!(trie.get(k.clone()).unwrap(), Some(v.clone()));
+        }
+        assert_eq!(trie.get(key(3, 3)).unwrap(), None);
+        assert_eq!(trie.get(key(1, 1)).unwrap(), None);
+    }
+
+    #[test]
+    fn byte_keys() {
+        let mut trie = Trie::new();
+        trie.insert(ByteKey::from(vec![0xde, 0xad]), vec![1u8])
+            .unwrap();
+        assert_eq!(
+            trie.get(NibbleKey::new(vec![0xd, 0xe, 0xa, 0xd])).unwrap(),
+            Some(vec![1u8])
+        );
+    }
+
+    #[test]
+    fn insert_overwrites() {
+        let mut trie: Trie = sample().into_iter().collect();
+        let hash = trie.root_hash();
+        assert_eq!(
+            trie.insert(key(2, 3), vec![42u8; 32]).unwrap(),
+            Some(vec![4u8; 32])
+        );
+        assert_eq!(trie.get(key(2, 3)).unwrap(), Some(vec![42u8; 32]));
+        assert_ne!(trie.root_hash(), hash);
+
+        trie.insert(key(2, 3), vec![4u8; 32]).unwrap();
+        assert_eq!(trie.root_hash(), hash);
+    }
+
+    #[test]
+    fn remove_restores_previous_structure() {
+        let keyvals = sample();
+        for i in 0..keyvals.len() {
+            let mut trie: Trie = keyvals.clone().into_iter().collect();
+            let (k, v) = keyvals[i].clone();
+            assert_eq!(trie.remove(k.clone()).unwrap(), Some(v));
+            assert_eq!(trie.remove(k.clone()).unwrap(), None);
+
+            let expected: Trie = keyvals
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, kv)| kv.clone())
+                .collect();
+            assert_eq!(trie, expected);
+        }
+    }
+
+    #[test]
+    fn remove_all_keys() {
+        let keyvals = sample();
+        let mut trie: Trie = keyvals.clone().into_iter().collect();
+        for (k, _) in keyvals.into_iter() {
+            trie.remove(k).unwrap();
+        }
+        assert_eq!(trie, Trie::new());
+    }
+
+    #[test]
+    fn remove_invalid_digit() {
+        let trie: Trie = sample().into_iter().collect();
+        assert!(remove_leaf(trie.root(), &[16u8; 32]).is_err());
+
+        let mut trie = Trie::with_width(4);
+        trie.insert(NibbleKey::new(vec![3u8; 8]), vec![1u8])
+            .unwrap();
+        assert!(remove_leaf(trie.root(), &[4u8; 8]).is_err());
+        assert!(remove_leaf(trie.root(), &[3, 3, 9, 3, 3, 3, 3, 3]).is_err());
+        assert!(trie.remove(NibbleKey::new(vec![4u8; 8])).is_err());
+    }
+
+    #[test]
+    fn prefix_keys() {
+        let short = ByteKey::from(vec![0xde]);
+        let long = ByteKey::from(vec![0xde, 0xad]);
+
+        let mut trie = Trie::new();
+        trie.insert(short.clone(), vec![1u8]).unwrap();
+        assert!(trie.insert(long.clone(), vec![2u8]).is_err());
+        assert_eq!(trie.get(short.clone()).unwrap(), Some(vec![1u8]));
+
+        let mut trie = Trie::new();
+        trie.insert(long.clone(), vec![2u8]).unwrap();
+        assert!(trie.insert(short.clone(), vec![1u8]).is_err());
+        assert_eq!(trie.get(long.clone()).unwrap(), Some(vec![2u8]));
+
+        // A key that stops within an extension, or at a full node
+        trie.insert(ByteKey::from(vec![0xde, 0xaf]), vec![3u8])
+            .unwrap();
+        assert!(trie.insert(short, vec![1u8]).is_err());
+        assert!(trie
+            .insert(NibbleKey::new(vec![0xd, 0xe, 0xa]), vec![1u8])
+            .is_err());
+        assert_eq!(trie.get(long).unwrap(), Some(vec![2u8]));
+    }
+
+    #[test]
+    fn prove_and_rebuild() {
+        let trie: Trie = sample().into_iter().collect();
+        let mut long = vec![4u8; 32];
+        long[30] = 2;
+        let keys = vec![key(8, 9), key(2, 0), NibbleKey::new(long), key(15, 3)];
+
+        let proof = trie.prove(keys.clone()).unwrap();
+        let rebuilt = Trie::from_root(rebuild(&mut vec![], &proof));
+        assert_eq!(rebuilt.root_hash(), trie.root_hash());
+        for k in keys.into_iter() {
+            assert_eq!(rebuilt.get(k.clone()).unwrap(), trie.get(k).unwrap());
+        }
+        // Keys that aren't part of the proof are hashed
+        assert!(rebuilt.get(key(1, 0)).is_err());
+    }
+
+    #[test]
+    fn arities() {
+        let keys: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i.wrapping_mul(37); 4]).collect();
+        for width in [2usize, 4, 16, 256].iter() {
+            let digits = |k: &[u8]| NibbleKey::from_bytes_with_width(k, *width);
+            let mut trie = Trie::with_width(*width);
+            for k in keys.iter() {
+                trie.insert(digits(k), k.clone()).unwrap();
+            }
+            for k in keys.iter() {
+                assert_eq!(trie.get(digits(k)).unwrap(), Some(k.clone()));
+            }
+
+            let proven: Vec<NibbleKey> = keys[..5].iter().map(|k| digits(k)).collect();
+            let proof = trie.prove(proven.clone()).unwrap();
+            let rebuilt = verify_multiproof_with_width(&trie.root_hash(), &proof, *width).unwrap();
+            assert_eq!(
+                get(&rebuilt, proven[3].as_ref()).unwrap(),
+                Some(keys[3].clone())
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_digits_for_width() {
+        let mut trie = Trie::with_width(4);
+        assert!(trie
+            .insert(NibbleKey::new(vec![4u8; 8]), vec![1u8])
+            .is_err());
+        assert!(trie.insert(NibbleKey::new(vec![3u8; 8]), vec![1u8]).is_ok());
+        assert!(trie.get(NibbleKey::new(vec![5u8; 8])).is_err());
+    }
+
+    #[test]
+    fn prove_missing_key() {
+        let trie: Trie = sample().into_iter().collect();
+        assert!(trie.prove(vec![key(3, 3)]).is_err());
+    }
+}
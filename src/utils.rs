@@ -1,6 +1,13 @@
-#[derive(Debug, PartialEq, Clone)]
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+// The largest number of children that a full node can have, so that
+// each digit of a key fits in a byte.
+pub const MAX_WIDTH: usize = 256;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct NibbleKey(Vec<u8>);
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 pub struct ByteKey(Vec<u8>);
 
 impl From<ByteKey> for NibbleKey {
@@ -25,6 +32,44 @@ impl NibbleKey {
         NibbleKey(nibbles.clone())
     }
 
+    // Create a key for a tree whose full nodes have `width` children,
+    // in which case each digit of the key has to be lower than `width`.
+    pub fn with_width(digits: Vec<u8>, width: usize) -> Self {
+        if digits.iter().any(|d| *d as usize >= width) {
+            panic!("Digit value is higher than {}", width - 1);
+        }
+        NibbleKey(digits)
+    }
+
+    // Create a key without checking its digits, for keys whose tree
+    // width isn't known, e.g. keys read from an encoded node or collected
+    // while walking a tree. The digits are checked against the width of
+    // the tree when the key is used to look into it.
+    pub fn from_digits_unchecked(digits: Vec<u8>) -> Self {
+        NibbleKey(digits)
+    }
+
+    // Split `bytes` into digits of a tree whose full nodes have `width`
+    // children, most significant bits first. Only widths of 2, 4, 16 and
+    // 256 are supported, so that a digit never spans two bytes.
+    pub fn from_bytes_with_width(bytes: &[u8], width: usize) -> Self {
+        let bits = match width {
+            2 => 1,
+            4 => 2,
+            16 => 4,
+            256 => 8,
+            _ => panic!("Unsupported width {}", width),
+        };
+        let mask = (width - 1) as u8;
+        let mut digits = Vec::with_capacity(bytes.len() * 8 / bits);
+        for byte in bytes.iter() {
+            for i in (0..8 / bits).rev() {
+                digits.push(((*byte as usize >> (i * bits)) as u8) & mask);
+            }
+        }
+        NibbleKey(digits)
+    }
+
     pub fn remove_prefix(&self, prefix_length: usize) -> Self {
         NibbleKey(self.0[prefix_length + 1..].to_vec())
     }
@@ -35,7 +80,7 @@ impl NibbleKey {
 
     // Find the length of the common prefix of two keys
     pub fn factor_length(&self, other: &Self) -> usize {
-        let (ref longuest, ref shortest) = if self.0.len() > other.0.len() {
+        let (longuest, shortest) = if self.0.len() > other.0.len() {
             (&self.0, &other.0)
         } else {
             (&other.0, &self.0)
@@ -43,7 +88,7 @@ impl NibbleKey {
         let mut firstdiffindex = shortest.len();
         for (i, &n) in shortest.iter().enumerate() {
             if n != longuest[i] {
-                firstdiffindex = i as usize;
+                firstdiffindex = i;
                 break;
             }
         }
@@ -52,19 +97,25 @@ impl NibbleKey {
     }
 }
 
+impl AsRef<[u8]> for NibbleKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl rlp::Encodable for NibbleKey {
     fn rlp_append(&self, s: &mut rlp::RlpStream) {
         s.append(&self.0);
     }
 }
 
-impl Into<Vec<u8>> for NibbleKey {
-    fn into(self) -> Vec<u8> {
-        self.0.clone()
+impl From<NibbleKey> for Vec<u8> {
+    fn from(key: NibbleKey) -> Self {
+        key.0
     }
 }
 
-impl std::ops::Index<usize> for NibbleKey {
+impl core::ops::Index<usize> for NibbleKey {
     type Output = u8;
 
     fn index(&self, i: usize) -> &u8 {
@@ -72,6 +123,18 @@ impl std::ops::Index<usize> for NibbleKey {
     }
 }
 
+impl From<Vec<u8>> for ByteKey {
+    fn from(bytes: Vec<u8>) -> Self {
+        ByteKey(bytes)
+    }
+}
+
+impl AsRef<[u8]> for ByteKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl From<NibbleKey> for ByteKey {
     fn from(key: NibbleKey) -> Self {
         let mut result = Vec::<u8>::new();
@@ -84,7 +147,7 @@ impl From<NibbleKey> for ByteKey {
             }
         }
         // Add the odd byte
-        if key.0.len() % 2 != 0 {
+        if !key.0.len().is_multiple_of(2) {
             result.push(saved);
         }
         ByteKey(result)
@@ -95,6 +158,27 @@ impl From<NibbleKey> for ByteKey {
 mod tests {
     use super::*;
 
+    #[test]
+    fn bytes_with_width() {
+        let bytes = [0xa5u8, 0x3c];
+        assert_eq!(
+            NibbleKey::from_bytes_with_width(&bytes, 16),
+            NibbleKey::from(ByteKey(bytes.to_vec()))
+        );
+        assert_eq!(
+            NibbleKey::from_bytes_with_width(&bytes, 4),
+            NibbleKey(vec![2, 2, 1, 1, 0, 3, 3, 0])
+        );
+        assert_eq!(
+            NibbleKey::from_bytes_with_width(&bytes[..1], 2),
+            NibbleKey(vec![1, 0, 1, 0, 0, 1, 0, 1])
+        );
+        assert_eq!(
+            NibbleKey::from_bytes_with_width(&bytes, 256),
+            NibbleKey(bytes.to_vec())
+        );
+    }
+
     #[test]
     fn test_nibble2bytes() {
         let bytes = ByteKey(vec![0xde, 0xad, 0xbe, 0xef]);
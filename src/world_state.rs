@@ -0,0 +1,290 @@
+use super::account::*;
+use super::secure_trie::SecureTrie;
+use super::*;
+use std::collections::HashMap;
+
+// A two-level state: a tree of accounts, indexed by the hash of their
+// address, whose leaves reference the root of a per-account storage
+// tree, indexed by the hash of the storage slot. The `storage_root` of
+// each account is kept in sync with its storage tree. Contract code is
+// stored separately, indexed by its hash.
+#[derive(Debug, Clone, Default)]
+pub struct WorldState {
+    accounts: SecureTrie,
+    storage: HashMap<ByteKey, SecureTrie>,
+    code: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl WorldState {
+    pub fn new() -> Self {
+        WorldState {
+            accounts: SecureTrie::new(),
+            storage: HashMap::new(),
+            code: HashMap::new(),
+        }
+    }
+
+    // The root hash of the account tree, which commits to the whole state.
+    pub fn root_hash(&self) -> Vec<u8> {
+        self.accounts.root_hash()
+    }
+
+    // The account tree.
+    pub fn accounts(&self) -> &SecureTrie {
+        &self.accounts
+    }
+
+    // The storage tree of the account at `address`, if it has storage.
+    pub fn storage_trie(&self, address: &ByteKey) -> Option<&SecureTrie> {
+        self.storage.get(address)
+    }
+
+    pub fn account(&self, address: &ByteKey) -> Result<Option<Account>, String> {
+        self.accounts.get_account(address)
+    }
+
+    // Store `account` at `address`, and return the previous account if
+    // there was one. The `storage_root` field is ignored and replaced
+    // with the root of the account's storage tree.
+    pub fn insert_account(
+        &mut self,
+        address: &ByteKey,
+        account: Account,
+    ) -> Result<Option<Account>, String> {
+        let storage_root = self.storage_root(address);
+        self.accounts.insert_account(
+            address,
+            &Account {
+                storage_root,
+                ..account
+            },
+        )
+    }
+
+    // Remove the account at `address` along with its storage, and return
+    // it if it was present.
+    pub fn remove_account(&mut self, address: &ByteKey) -> Result<Option<Account>, String> {
+        let previous = self.account(address)?;
+        if previous.is_some() {
+            self.accounts.remove(address)?;
+            self.storage.remove(address);
+        }
+        Ok(previous)
+    }
+
+    pub fn storage(&self, address: &ByteKey, slot: &ByteKey) -> Result<Option<Vec<u8>>, String> {
+        match self.storage.get(address) {
+            Some(trie) => trie.get(slot),
+            None => Ok(None),
+        }
+    }
+
+    // Set the value of a storage slot of the account at `address`, and
+    // return its previous value. The account has to exist.
+    pub fn insert_storage<V: Into<Vec<u8>>>(
+        &mut self,
+        address: &ByteKey,
+        slot: &ByteKey,
+        value: V,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.check_account(address)?;
+        let previous = self
+            .storage
+            .entry(address.clone())
+            .or_default()
+            .insert(slot, value)?;
+        self.update_storage_root(address)?;
+        Ok(previous)
+    }
+
+    // Clear a storage slot of the account at `address`, and return its
+    // previous value. The account has to exist.
+    pub fn remove_storage(
+        &mut self,
+        address: &ByteKey,
+        slot: &ByteKey,
+    ) -> Result<Option<Vec<u8>>, String> {
+        self.check_account(address)?;
+        let previous = match self.storage.get_mut(address) {
+            Some(trie) => trie.remove(slot)?,
+            None => return Ok(None),
+        };
+        if self.storage[address].trie().root().iter().next().is_none() {
+            self.storage.remove(address);
+        }
+        self.update_storage_root(address)?;
+        Ok(previous)
+    }
+
+    // The contract code of the account at `address`, if the account
+    // exists. Accounts without code return an empty vector.
+    pub fn code(&self, address: &ByteKey) -> Result<Option<Vec<u8>>, String> {
+        let account = match self.account(address)? {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+        if account.code_hash == EMPTY_CODE_HASH {
+            return Ok(Some(vec![]));
+        }
+        match self.code.get(&account.code_hash) {
+            Some(code) => Ok(Some(code.clone())),
+            None => Err(format!("Missing code for account {:?}", address)),
+        }
+    }
+
+    // Set the contract code of the account at `address`, and update its
+    // `code_hash`. The account has to exist.
+    pub fn insert_code(&mut self, address: &ByteKey, code: Vec<u8>) -> Result<(), String> {
+        let account = self
+            .account(address)?
+            .ok_or_else(|| format!("No account at {:?}", address))?;
+        let hash = code_hash(&code);
+        self.code.insert(hash.clone(), code);
+        self.insert_account(
+            address,
+            Account {
+                code_hash: hash,
+                ..account
+            },
+        )?;
+        Ok(())
+    }
+
+    fn check_account(&self, address: &ByteKey) -> Result<(), String> {
+        match self.account(address)? {
+            Some(_) => Ok(()),
+            None => Err(format!("No account at {:?}", address)),
+        }
+    }
+
+    fn storage_root(&self, address: &ByteKey) -> Vec<u8> {
+        match self.storage.get(address) {
+            Some(trie) => trie.root_hash(),
+            None => SecureTrie::new().root_hash(),
+        }
+    }
+
+    // Recompute the `storage_root` of the account at `address` after its
+    // storage has changed.
+    fn update_storage_root(&mut self, address: &ByteKey) -> Result<(), String> {
+        let account = self
+            .account(address)?
+            .ok_or_else(|| format!("No account at {:?}", address))?;
+        self.insert_account(address, account)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(i: u8) -> ByteKey {
+        ByteKey::from(vec![i; 20])
+    }
+
+    fn slot(i: u8) -> ByteKey {
+        ByteKey::from(vec![i; 32])
+    }
+
+    fn account(i: u8) -> Account {
+        Account {
+            nonce: i as u64,
+            balance: i as u128 * 1000,
+            ..Account::default()
+        }
+    }
+
+    #[test]
+    fn storage_root_follows_storage() {
+        let mut state = WorldState::new();
+        state.insert_account(&address(1), account(1)).unwrap();
+        let empty = state.root_hash();
+
+        state
+            .insert_storage(&address(1), &slot(1), vec![1u8])
+            .unwrap();
+        state
+            .insert_storage(&address(1), &slot(2), vec![2u8])
+            .unwrap();
+        assert_ne!(state.root_hash(), empty);
+        assert_eq!(
+            state.storage(&address(1), &slot(2)).unwrap(),
+            Some(vec![2u8])
+        );
+        assert_eq!(
+            state.account(&address(1)).unwrap().unwrap().storage_root,
+            state.storage_trie(&address(1)).unwrap().root_hash()
+        );
+
+        assert_eq!(
+            state.remove_storage(&address(1), &slot(1)).unwrap(),
+            Some(vec![1u8])
+        );
+        state.remove_storage(&address(1), &slot(2)).unwrap();
+        assert!(state.storage_trie(&address(1)).is_none());
+        assert_eq!(state.root_hash(), empty);
+    }
+
+    #[test]
+    fn state_root_matches_account_tree() {
+        let mut state = WorldState::new();
+        let mut expected = SecureTrie::new();
+        for i in 0..5u8 {
+            state.insert_account(&address(i), account(i)).unwrap();
+            let mut storage = SecureTrie::new();
+            for j in 0..i {
+                state
+                    .insert_storage(&address(i), &slot(j), vec![j; 4])
+                    .unwrap();
+                storage.insert(&slot(j), vec![j; 4]).unwrap();
+            }
+            let acc = Account {
+                storage_root: storage.root_hash(),
+                ..account(i)
+            };
+            expected.insert_account(&address(i), &acc).unwrap();
+        }
+        assert_eq!(state.root_hash(), expected.root_hash());
+
+        // Updating an account keeps its storage root
+        state.insert_account(&address(3), account(9)).unwrap();
+        assert_eq!(
+            state.account(&address(3)).unwrap().unwrap().storage_root,
+            state.storage_trie(&address(3)).unwrap().root_hash()
+        );
+    }
+
+    #[test]
+    fn insert_and_get_code() {
+        let mut state = WorldState::new();
+        assert!(state.insert_code(&address(1), vec![0x60, 0x00]).is_err());
+        assert_eq!(state.code(&address(1)).unwrap(), None);
+
+        state.insert_account(&address(1), account(1)).unwrap();
+        assert_eq!(state.code(&address(1)).unwrap(), Some(vec![]));
+        state.insert_code(&address(1), vec![0x60, 0x00]).unwrap();
+        assert_eq!(state.code(&address(1)).unwrap(), Some(vec![0x60, 0x00]));
+        assert_eq!(
+            state.account(&address(1)).unwrap().unwrap().code_hash,
+            code_hash(&[0x60, 0x00])
+        );
+    }
+
+    #[test]
+    fn storage_requires_account() {
+        let mut state = WorldState::new();
+        assert!(state
+            .insert_storage(&address(1), &slot(1), vec![1u8])
+            .is_err());
+        assert_eq!(state.storage(&address(1), &slot(1)).unwrap(), None);
+
+        state.insert_account(&address(1), account(1)).unwrap();
+        state
+            .insert_storage(&address(1), &slot(1), vec![1u8])
+            .unwrap();
+        assert_eq!(state.remove_account(&address(1)).unwrap().unwrap().nonce, 1);
+        assert!(state.storage_trie(&address(1)).is_none());
+        assert_eq!(state.root_hash(), WorldState::new().root_hash());
+    }
+}